@@ -0,0 +1,45 @@
+use crate::{audit::AuditEvent, config::CoreConfig};
+use rocket::{
+    http::Status,
+    outcome::try_outcome,
+    request::{FromRequest, Outcome},
+    Request,
+};
+
+// Requests bearing the configured admin token. Admin endpoints don't exist without one, so
+// requests are answered 404 when no admin token is configured and 401 when the token is wrong.
+// Every admitted request is recorded in the audit log, naming the route and the path called.
+pub struct AdminToken;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminToken {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let config = try_outcome!(request.guard::<&CoreConfig>().await);
+        let admin_token = match config.admin_token() {
+            Some(admin_token) => admin_token,
+            None => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let token = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|header| header.strip_prefix("Bearer "));
+        match token {
+            Some(token) if admin_token.matches(token) => {
+                let action = request
+                    .route()
+                    .and_then(|route| route.name.as_deref())
+                    .unwrap_or_else(|| request.method().as_str());
+                config
+                    .audit(AuditEvent::AdminAction {
+                        action: action.to_string(),
+                        target: request.uri().to_string(),
+                    })
+                    .await;
+                Outcome::Success(AdminToken)
+            }
+            _ => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// Notifications to on-call engineers when calls to a plugin keep failing
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::{config::CoreConfig, corekeys::NotificationSigner, metrics};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Orbit, Rocket,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+// Interval at which failure counts are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+fn default_threshold() -> u64 {
+    5
+}
+
+fn default_cooldown() -> u64 {
+    15 * 60
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct AlertConfig {
+    webhook_url: String,
+    // Consecutive failed calls to a plugin after which to alert
+    #[serde(default = "default_threshold")]
+    threshold: u64,
+    // Seconds before alerting about the same plugin again
+    #[serde(default = "default_cooldown")]
+    cooldown: u64,
+    // Post messages in the format of Slack incoming webhooks
+    #[serde(default)]
+    slack: bool,
+}
+
+impl AlertConfig {
+    // Methods to alert about, given their consecutive failures and when they were last alerted
+    fn due(
+        &self,
+        failures: &BTreeMap<String, u64>,
+        last_alerts: &HashMap<String, Instant>,
+        now: Instant,
+    ) -> Vec<(String, u64)> {
+        let cooldown = Duration::from_secs(self.cooldown);
+        failures
+            .iter()
+            .filter(|(_, count)| **count >= self.threshold)
+            .filter(|(method, _)| {
+                !matches!(last_alerts.get(*method), Some(last) if now.duration_since(*last) < cooldown)
+            })
+            .map(|(method, count)| (method.clone(), *count))
+            .collect()
+    }
+
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        signer: &NotificationSigner,
+        server_url: &str,
+        method: &str,
+        failures: u64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let body = if self.slack {
+            json!({
+                "text": format!(
+                    "Plugin {} of {} failed {} consecutive calls",
+                    method, server_url, failures
+                ),
+            })
+        } else {
+            json!({
+                "alert": "plugin_failures",
+                "server_url": server_url,
+                "method": method,
+                "consecutive_failures": failures,
+            })
+        };
+        // Signed with detached payload, so the body stays readable for services like Slack
+        let body = body.to_string();
+        client
+            .post(&self.webhook_url)
+            .header("Content-Type", "application/json")
+            .header("X-JWS-Signature", signer.sign_detached(body.as_bytes())?)
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+// Plugins can be shared between tenants, so alerts are configured globally
+pub struct AlertFairing;
+
+#[rocket::async_trait]
+impl Fairing for AlertFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Plugin failure alerts",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let config = match rocket.state::<CoreConfig>() {
+            Some(config) => config,
+            None => return,
+        };
+        let alerts = match config.plugin_alerts() {
+            Some(alerts) => alerts.clone(),
+            None => return,
+        };
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                log::error!("Could not set up plugin failure alerts: {}", e);
+                return;
+            }
+        };
+        let server_url = config.server_url().to_string();
+        let signer = config.notification_signer();
+        let shutdown = rocket.shutdown();
+        rocket::tokio::spawn(async move {
+            let mut last_alerts = HashMap::new();
+            loop {
+                rocket::tokio::select! {
+                    _ = shutdown.clone() => break,
+                    _ = rocket::tokio::time::sleep(CHECK_INTERVAL) => {}
+                }
+                let now = Instant::now();
+                for (method, failures) in
+                    alerts.due(&metrics::consecutive_failures(), &last_alerts, now)
+                {
+                    log::warn!(
+                        "Plugin {} failed {} consecutive calls, alerting",
+                        method,
+                        failures
+                    );
+                    match alerts
+                        .send(&client, &signer, &server_url, &method, failures)
+                        .await
+                    {
+                        Ok(()) => {
+                            last_alerts.insert(method, now);
+                        }
+                        Err(e) => log::error!("Could not send plugin failure alert: {}", e),
+                    }
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        sync::Arc,
+        time::{Duration, Instant},
+    };
+
+    use httpmock::MockServer;
+    use josekit::jws::HS256;
+    use serde_json::json;
+
+    use super::AlertConfig;
+    use crate::corekeys::NotificationSigner;
+
+    #[test]
+    fn test_due_alerts() {
+        let alerts = serde_json::from_value::<AlertConfig>(json!({
+            "webhook_url": "https://example.com/alerts",
+            "threshold": 3,
+            "cooldown": 60,
+        }))
+        .unwrap();
+        let mut failures = BTreeMap::new();
+        failures.insert("call".to_string(), 3);
+        failures.insert("chat".to_string(), 2);
+        failures.insert("irma".to_string(), 10);
+        let now = Instant::now();
+        let mut last_alerts = HashMap::new();
+        last_alerts.insert("irma".to_string(), now);
+
+        assert_eq!(
+            alerts.due(&failures, &last_alerts, now + Duration::from_secs(30)),
+            vec![("call".to_string(), 3)]
+        );
+        assert_eq!(
+            alerts.due(&failures, &last_alerts, now + Duration::from_secs(60)),
+            vec![("call".to_string(), 3), ("irma".to_string(), 10)]
+        );
+    }
+
+    #[test]
+    fn test_send_alert() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/alerts")
+                .header_exists("X-JWS-Signature")
+                .json_body(json!({
+                    "text": "Plugin call of https://core.example.com failed 5 consecutive calls",
+                }));
+            then.status(200);
+        });
+
+        let alerts = serde_json::from_value::<AlertConfig>(json!({
+            "webhook_url": format!("{}/alerts", server.base_url()),
+            "slack": true,
+        }))
+        .unwrap();
+        tokio_test::block_on(alerts.send(
+            &reqwest::Client::new(),
+            &NotificationSigner::new(
+                Arc::new(HS256.signer_from_bytes(b"alert_signing_key").unwrap()),
+                None,
+            ),
+            "https://core.example.com",
+            "call",
+            5,
+        ))
+        .unwrap();
+        mock.assert();
+    }
+}
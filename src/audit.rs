@@ -0,0 +1,623 @@
+use std::{
+    fmt::Debug,
+    io::{BufRead, BufReader, Write},
+    os::unix::net::UnixDatagram,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    clientip::current_client_ip,
+    methods::Tag,
+    session::{AuthFailure, ConsentRecord},
+};
+use rocket::tokio::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+fn default_syslog_socket() -> PathBuf {
+    PathBuf::from("/dev/log")
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "sink", rename_all = "lowercase")]
+pub enum AuditSinkConfig {
+    File {
+        path: PathBuf,
+    },
+    Syslog {
+        #[serde(default = "default_syslog_socket")]
+        socket: PathBuf,
+    },
+    Http {
+        url: String,
+    },
+}
+
+// Auditable events. These must never contain attribute values.
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    SessionStart {
+        purpose: String,
+        auth_method: Option<Tag>,
+        comm_method: Option<Tag>,
+        requestor: Option<String>,
+        success: bool,
+    },
+    ShimDelivery {
+        purpose: String,
+        session_id: Option<String>,
+        auth_method: Option<String>,
+        success: bool,
+    },
+    // Call of an administrative endpoint, by route and the path it was called on
+    AdminAction {
+        action: String,
+        target: String,
+    },
+    AuthFallback {
+        purpose: String,
+        unavailable_auth_method: Tag,
+        auth_method: Tag,
+    },
+    AuthFailure {
+        purpose: String,
+        reason: AuthFailure,
+    },
+    Erasure {
+        sessions: usize,
+        consent_records: usize,
+    },
+    Consent(ConsentRecord),
+    // Consent records replaced by stubs, by hash
+    ConsentsErased {
+        hashes: Vec<String>,
+    },
+    // Records removed past their retention, the last of which the remaining chain links to
+    Purge {
+        purged: usize,
+        last_hash: Option<String>,
+    },
+}
+
+// Event of consent records, and of the stubs left in their place once erased
+const CONSENT_EVENT: &str = "consent";
+const ERASED_EVENT: &str = "erased";
+const PURGE_EVENT: &str = "purge";
+const CONSENTS_ERASED_EVENT: &str = "consents_erased";
+
+// Hash of the (non-existent) record preceding the first record in a chain
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+pub struct AuditLog {
+    sink: AuditSinkConfig,
+    last_hash: Mutex<String>,
+}
+
+impl Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog")
+            .field("sink", &self.sink)
+            .finish()
+    }
+}
+
+impl From<AuditSinkConfig> for AuditLog {
+    fn from(sink: AuditSinkConfig) -> Self {
+        // Continue an existing chain when appending to a file
+        let last_hash = match &sink {
+            AuditSinkConfig::File { path } => std::fs::File::open(path)
+                .ok()
+                .and_then(|file| BufReader::new(file).lines().map_while(Result::ok).last())
+                .and_then(|line| serde_json::from_str::<serde_json::Value>(&line).ok())
+                .and_then(|record| record.get("hash")?.as_str().map(|h| h.to_string())),
+            _ => None,
+        };
+
+        AuditLog {
+            sink,
+            last_hash: Mutex::new(last_hash.unwrap_or_else(|| GENESIS_HASH.to_string())),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn record_timestamp(line: &str) -> Option<u64> {
+    serde_json::from_str::<serde_json::Value>(line)
+        .ok()?
+        .get("timestamp")?
+        .as_u64()
+}
+
+fn consent_record(line: &str) -> Option<ConsentRecord> {
+    let record = serde_json::from_str::<serde_json::Value>(line).ok()?;
+    if record.get("event")?.as_str()? != CONSENT_EVENT {
+        return None;
+    }
+    serde_json::from_value(record).ok()
+}
+
+// Stub of an erased record, keeping only what links it into the chain, and its hash
+fn erased_stub(line: &str) -> (String, String) {
+    let record = serde_json::from_str::<serde_json::Value>(line).unwrap_or_default();
+    let stub = serde_json::json!({
+        "event": ERASED_EVENT,
+        "erased_event": record["event"],
+        "timestamp": record["timestamp"],
+        "prev_hash": record["prev_hash"],
+        "content_hash": record["content_hash"],
+        "hash": record["hash"],
+    });
+    (
+        stub.to_string(),
+        record["hash"].as_str().unwrap_or_default().to_string(),
+    )
+}
+
+fn read_file(path: &Path) -> std::io::Result<String> {
+    match std::fs::read_to_string(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+        contents => contents,
+    }
+}
+
+// Replace a file by the given lines, without leaving it partially written
+fn rewrite<T: AsRef<str>>(path: &Path, lines: &[T]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("purge");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    for line in lines {
+        writeln!(file, "{}", line.as_ref())?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+fn chain_hash(record: &serde_json::Value) -> String {
+    format!("{:x}", Sha256::digest(record.to_string().as_bytes()))
+}
+
+// Hash of a record in the chain. It covers the content through its hash, so stubs of erased
+// records can still be checked for the event they replace and their place in the chain.
+fn record_hash(event: &serde_json::Value, record: &serde_json::Value) -> String {
+    chain_hash(&serde_json::json!({
+        "event": event,
+        "timestamp": record["timestamp"],
+        "prev_hash": record["prev_hash"],
+        "content_hash": record["content_hash"],
+    }))
+}
+
+// Add the content hash and hash to a record linked to its predecessor by prev_hash
+fn seal(mut record: serde_json::Value) -> (String, String) {
+    record["content_hash"] = chain_hash(&record).into();
+    let hash = record_hash(&record["event"], &record);
+    record["hash"] = hash.as_str().into();
+    (record.to_string(), hash)
+}
+
+// Record of an event following the record with last_hash, and its hash
+fn chained(event: &AuditEvent, last_hash: &str) -> serde_json::Result<(String, String)> {
+    let mut record = serde_json::to_value(event)?;
+    record["timestamp"] = unix_time().into();
+    if let Some(ip) = current_client_ip() {
+        record["client_ip"] = ip.to_string().into();
+    }
+    record["prev_hash"] = last_hash.into();
+    Ok(seal(record))
+}
+
+// Check the chain of records of a file sink, returning how many there are. The chain starts at
+// the genesis hash, or at the last record removed by a purge recorded further on. Erased records
+// only link the chain, as their content is gone, and must be consent records whose erasure was
+// recorded further on.
+pub fn verify_chain(contents: &str) -> Result<usize, String> {
+    let records = contents
+        .lines()
+        .enumerate()
+        .map(|(i, line)| {
+            serde_json::from_str::<serde_json::Value>(line)
+                .map_err(|e| format!("Record {} is not valid json: {}", i + 1, e))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let anchors: Vec<&str> = records
+        .iter()
+        .filter(|record| record["event"] == PURGE_EVENT)
+        .filter_map(|record| record["last_hash"].as_str())
+        .collect();
+    let erased: Vec<&str> = records
+        .iter()
+        .filter(|record| record["event"] == CONSENTS_ERASED_EVENT)
+        .filter_map(|record| record["hashes"].as_array())
+        .flatten()
+        .filter_map(|hash| hash.as_str())
+        .collect();
+
+    let mut prev_hash = None;
+    for (i, record) in records.iter().enumerate() {
+        let (claimed_prev_hash, hash) =
+            match (record["prev_hash"].as_str(), record["hash"].as_str()) {
+                (Some(prev_hash), Some(hash)) => (prev_hash, hash),
+                _ => return Err(format!("Record {} is not chained", i + 1)),
+            };
+        match prev_hash {
+            Some(prev_hash) if claimed_prev_hash != prev_hash => {
+                return Err(format!("Record {} does not follow the one before", i + 1))
+            }
+            None if claimed_prev_hash != GENESIS_HASH && !anchors.contains(&claimed_prev_hash) => {
+                return Err("The first record follows neither the genesis hash nor a purge".into())
+            }
+            _ => {}
+        }
+        let event = if record["event"] == ERASED_EVENT {
+            if record["erased_event"] != CONSENT_EVENT {
+                return Err(format!(
+                    "Record {} was erased, but is no consent record",
+                    i + 1
+                ));
+            }
+            if !erased.contains(&hash) {
+                return Err(format!(
+                    "Record {} was erased without an erasure record",
+                    i + 1
+                ));
+            }
+            &record["erased_event"]
+        } else {
+            let mut content = record.clone();
+            if let Some(content) = content.as_object_mut() {
+                content.remove("hash");
+                content.remove("content_hash");
+            }
+            if record["content_hash"].as_str() != Some(chain_hash(&content).as_str()) {
+                return Err(format!("Record {} was altered", i + 1));
+            }
+            &record["event"]
+        };
+        if record_hash(event, record) != hash {
+            return Err(format!("Record {} was altered", i + 1));
+        }
+        prev_hash = Some(hash);
+    }
+    Ok(records.len())
+}
+
+impl AuditLog {
+    fn file(&self) -> Option<&Path> {
+        match &self.sink {
+            AuditSinkConfig::File { path } => Some(path),
+            _ => None,
+        }
+    }
+
+    // Whether core can read records back, which it can only from a file sink
+    pub fn is_readable(&self) -> bool {
+        self.file().is_some()
+    }
+
+    pub async fn record(&self, event: AuditEvent) {
+        // Hold the lock until the record is written, so the sink sees records in chain order
+        let mut last_hash = self.last_hash.lock().await;
+
+        let (record, hash) = match chained(&event, &last_hash) {
+            Ok(chained) => chained,
+            Err(e) => {
+                log::error!("Could not serialize audit record: {}", e);
+                return;
+            }
+        };
+        match self.write(&record).await {
+            Ok(()) => *last_hash = hash,
+            Err(e) => log::error!("Could not write audit record: {}", e),
+        }
+    }
+
+    // Remove records older than retention from a file sink, returning how many were removed. A
+    // purge record is appended in the same write, anchoring the remaining chain.
+    pub async fn purge(&self, retention: Duration) -> std::io::Result<usize> {
+        let path = match self.file() {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        // Hold the lock, so no records are appended while the file is rewritten
+        let mut last_hash = self.last_hash.lock().await;
+        let contents = read_file(path)?;
+        let cutoff = unix_time().saturating_sub(retention.as_secs());
+        let (removed, mut kept): (Vec<&str>, Vec<&str>) = contents.lines().partition(
+            |line| matches!(record_timestamp(line), Some(timestamp) if timestamp < cutoff),
+        );
+        if removed.is_empty() {
+            return Ok(0);
+        }
+
+        let purge = AuditEvent::Purge {
+            purged: removed.len(),
+            last_hash: removed
+                .last()
+                .and_then(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+                .and_then(|record| record.get("hash")?.as_str().map(|h| h.to_string())),
+        };
+        let (record, hash) = chained(&purge, &last_hash)?;
+        kept.push(&record);
+        rewrite(path, &kept)?;
+        *last_hash = hash;
+        Ok(removed.len())
+    }
+
+    // Consent records in a file sink
+    pub async fn consents(&self) -> std::io::Result<Vec<ConsentRecord>> {
+        let path = match self.file() {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+        let _last_hash = self.last_hash.lock().await;
+        Ok(read_file(path)?
+            .lines()
+            .filter_map(consent_record)
+            .collect())
+    }
+
+    // Replace the consent records matching filter in a file sink by stubs, returning how many
+    // were erased. The stubs keep the hashes, so the records around them still link up, and the
+    // erasure is recorded in the same write.
+    pub async fn erase_consents(
+        &self,
+        filter: impl Fn(&ConsentRecord) -> bool,
+    ) -> std::io::Result<usize> {
+        let path = match self.file() {
+            Some(path) => path,
+            None => return Ok(0),
+        };
+        let mut last_hash = self.last_hash.lock().await;
+        let mut hashes = vec![];
+        let mut lines: Vec<String> = read_file(path)?
+            .lines()
+            .map(|line| match consent_record(line) {
+                Some(record) if filter(&record) => {
+                    let (stub, hash) = erased_stub(line);
+                    hashes.push(hash);
+                    stub
+                }
+                _ => line.to_string(),
+            })
+            .collect();
+        if hashes.is_empty() {
+            return Ok(0);
+        }
+
+        let erased = hashes.len();
+        let (record, hash) = chained(&AuditEvent::ConsentsErased { hashes }, &last_hash)?;
+        lines.push(record);
+        rewrite(path, &lines)?;
+        *last_hash = hash;
+        Ok(erased)
+    }
+
+    // Number of records and timestamp of the oldest, for file sinks only
+    pub async fn stats(&self) -> Option<(usize, Option<u64>)> {
+        let path = self.file()?;
+        let _last_hash = self.last_hash.lock().await;
+        let contents = std::fs::read_to_string(path).unwrap_or_default();
+        let oldest = contents.lines().filter_map(record_timestamp).min();
+        Some((contents.lines().count(), oldest))
+    }
+
+    async fn write(&self, line: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match &self.sink {
+            AuditSinkConfig::File { path } => {
+                let mut file = std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                writeln!(file, "{}", line)?;
+            }
+            AuditSinkConfig::Syslog { socket } => {
+                // Facility log audit (13), severity informational (6)
+                let socket_conn = UnixDatagram::unbound()?;
+                socket_conn
+                    .send_to(format!("<110>id-contact-core: {}", line).as_bytes(), socket)?;
+            }
+            AuditSinkConfig::Http { url } => {
+                let client = reqwest::Client::builder()
+                    .timeout(std::time::Duration::from_secs(5))
+                    .build()?;
+                client
+                    .post(url)
+                    .header("Content-Type", "application/json")
+                    .body(line.to_string())
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, time::Duration};
+
+    use super::{
+        erased_stub, seal, verify_chain, AuditEvent, AuditLog, AuditSinkConfig, GENESIS_HASH,
+    };
+    use crate::{methods::Tag, session::ConsentRecord};
+
+    fn read_records(path: &std::path::Path) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    fn shim_delivery(success: bool) -> AuditEvent {
+        AuditEvent::ShimDelivery {
+            purpose: "test".into(),
+            session_id: Some("session".into()),
+            auth_method: Some("irma".into()),
+            success,
+        }
+    }
+
+    #[test]
+    fn test_file_chain() {
+        let path = std::env::temp_dir().join(format!("core-audit-test-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let log = AuditLog::from(AuditSinkConfig::File { path: path.clone() });
+        tokio_test::block_on(log.record(AuditEvent::SessionStart {
+            purpose: "test".into(),
+            auth_method: Tag::try_from("irma").ok(),
+            comm_method: None,
+            requestor: Some("test".into()),
+            success: true,
+        }));
+        tokio_test::block_on(log.record(shim_delivery(true)));
+
+        // A new log on the same file must continue the existing chain
+        let log = AuditLog::from(AuditSinkConfig::File { path: path.clone() });
+        tokio_test::block_on(log.record(AuditEvent::AdminAction {
+            action: "revoke_requestor".into(),
+            target: "/admin/requestors/municipality".into(),
+        }));
+
+        let records = read_records(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["event"], "session_start");
+        assert_eq!(records[0]["prev_hash"], GENESIS_HASH);
+        assert_eq!(records[1]["purpose"], "test");
+        assert_eq!(records[1]["session_id"], "session");
+        assert_eq!(records[2]["event"], "admin_action");
+        assert_eq!(records[2]["target"], "/admin/requestors/municipality");
+
+        assert_eq!(records[1]["prev_hash"], records[0]["hash"]);
+        assert_eq!(records[2]["prev_hash"], records[1]["hash"]);
+        let contents = records
+            .iter()
+            .map(|record| record.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(verify_chain(&contents), Ok(3));
+    }
+
+    #[test]
+    fn test_purge() {
+        let path =
+            std::env::temp_dir().join(format!("core-audit-purge-{}.log", std::process::id()));
+        let (old, old_hash) = seal(serde_json::json!({
+            "event": "shim_delivery",
+            "success": false,
+            "timestamp": 1000,
+            "prev_hash": GENESIS_HASH,
+        }));
+        std::fs::write(&path, format!("{}\n", old)).unwrap();
+        let log = AuditLog::from(AuditSinkConfig::File { path: path.clone() });
+        tokio_test::block_on(log.record(shim_delivery(true)));
+        assert_eq!(tokio_test::block_on(log.stats()), Some((2, Some(1000))));
+
+        let purged = tokio_test::block_on(log.purge(Duration::from_secs(24 * 60 * 60))).unwrap();
+        tokio_test::block_on(log.record(shim_delivery(true)));
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let records = read_records(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(purged, 1);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["success"], true);
+        assert_eq!(records[1]["event"], "purge");
+        assert_eq!(records[1]["purged"], 1);
+        assert_eq!(records[1]["last_hash"], old_hash.as_str());
+        assert_eq!(records[2]["prev_hash"], records[1]["hash"]);
+
+        // The purge record anchors the remaining chain, which can't be cut off without one
+        assert_eq!(verify_chain(&contents), Ok(3));
+        let lines: Vec<&str> = contents.lines().collect();
+        assert!(verify_chain(&lines[2..].join("\n")).is_err());
+        assert!(verify_chain(&contents.replace("true", "false")).is_err());
+
+        // Other sinks are not purged by core
+        let log = AuditLog::from(AuditSinkConfig::Http {
+            url: "http://localhost".into(),
+        });
+        assert_eq!(tokio_test::block_on(log.stats()), None);
+    }
+
+    #[test]
+    fn test_consents() {
+        let path =
+            std::env::temp_dir().join(format!("core-audit-consents-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let record = |session_id: &str| ConsentRecord {
+            session_id: session_id.into(),
+            purpose: "report_move".into(),
+            attributes: vec!["email".into()],
+            alternative_attributes: vec![],
+            auth_method: Tag::try_from("irma").ok(),
+            comm_method: None,
+            state: Some("reference".into()),
+            time: 100,
+        };
+
+        let log = AuditLog::from(AuditSinkConfig::File { path: path.clone() });
+        assert!(log.is_readable());
+        tokio_test::block_on(log.record(AuditEvent::Consent(record("a"))));
+        tokio_test::block_on(log.record(shim_delivery(true)));
+        tokio_test::block_on(log.record(AuditEvent::Consent(record("b"))));
+        assert_eq!(
+            tokio_test::block_on(log.consents()).unwrap(),
+            vec![record("a"), record("b")]
+        );
+
+        let erased =
+            tokio_test::block_on(log.erase_consents(|record| record.session_id == "a")).unwrap();
+        assert_eq!(erased, 1);
+        assert_eq!(
+            tokio_test::block_on(log.consents()).unwrap(),
+            vec![record("b")]
+        );
+
+        // The stub keeps the erased record's place in the chain, but none of its content, and
+        // the erasure itself is chained
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(verify_chain(&contents), Ok(4));
+        let records = read_records(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0]["event"], "erased");
+        assert_eq!(records[0]["erased_event"], "consent");
+        assert!(records[0].get("session_id").is_none());
+        assert_eq!(records[0]["prev_hash"], GENESIS_HASH);
+        assert_eq!(records[1]["prev_hash"], records[0]["hash"]);
+        assert_eq!(records[3]["event"], "consents_erased");
+        assert_eq!(
+            records[3]["hashes"],
+            serde_json::json!([records[0]["hash"]])
+        );
+        assert_eq!(records[3]["prev_hash"], records[2]["hash"]);
+
+        // Other records can't be replaced by stubs, whatever event they claim to replace
+        let lines: Vec<&str> = contents.lines().collect();
+        let (stub, _) = erased_stub(lines[1]);
+        let stubbed = [lines[0], &stub, lines[2], lines[3]].join("\n");
+        assert!(verify_chain(&stubbed).is_err());
+        let forged = stub.replace(
+            "\"erased_event\":\"shim_delivery\"",
+            "\"erased_event\":\"consent\"",
+        );
+        assert_ne!(forged, stub);
+        let forged = [lines[0], &forged, lines[2], lines[3]].join("\n");
+        assert!(verify_chain(&forged).is_err());
+
+        // Nor can consent records be erased without recording it
+        let (stub, _) = erased_stub(lines[2]);
+        let unrecorded = [lines[0], lines[1], &stub, lines[3]].join("\n");
+        assert!(verify_chain(&unrecorded).is_err());
+    }
+}
@@ -0,0 +1,122 @@
+// Limit on concurrent session starts, so slow plugins don't pile up unbounded outbound calls.
+// Starts beyond the limit wait in a queue for a while, and are shed when it is full.
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::error::Error;
+use rocket::tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use serde::{Deserialize, Serialize};
+
+fn default_max_wait() -> u64 {
+    2000
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BackpressureConfig {
+    max_concurrent: usize,
+    // Starts waiting for a slot, beyond which further starts are refused right away
+    #[serde(default)]
+    queue_depth: usize,
+    // Milliseconds a queued start waits for a slot
+    #[serde(default = "default_max_wait")]
+    max_wait: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "BackpressureConfig")]
+pub struct Backpressure {
+    config: BackpressureConfig,
+    slots: Arc<Semaphore>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl From<BackpressureConfig> for Backpressure {
+    fn from(config: BackpressureConfig) -> Self {
+        Backpressure {
+            slots: Arc::new(Semaphore::new(config.max_concurrent)),
+            queued: Arc::new(AtomicUsize::new(0)),
+            config,
+        }
+    }
+}
+
+impl Serialize for Backpressure {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.config.serialize(serializer)
+    }
+}
+
+impl Backpressure {
+    // Slot for a session start, held until the start is done
+    pub async fn acquire(&self) -> Result<OwnedSemaphorePermit, Error> {
+        if let Ok(permit) = self.slots.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        let overloaded = || Error::Overloaded {
+            retry_after: (self.config.max_wait / 1000).max(1),
+        };
+
+        if self.queued.fetch_add(1, Ordering::SeqCst) >= self.config.queue_depth {
+            self.queued.fetch_sub(1, Ordering::SeqCst);
+            log::warn!("Shedding session start, queue of starts is full");
+            return Err(overloaded());
+        }
+        let permit = timeout(
+            Duration::from_millis(self.config.max_wait),
+            self.slots.clone().acquire_owned(),
+        )
+        .await;
+        self.queued.fetch_sub(1, Ordering::SeqCst);
+        match permit {
+            Ok(Ok(permit)) => Ok(permit),
+            _ => {
+                log::warn!("Shedding session start, no slot freed up in time");
+                Err(overloaded())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::Backpressure;
+    use crate::error::Error;
+
+    #[test]
+    fn test_backpressure() {
+        let backpressure: Backpressure = serde_json::from_value(json!({
+            "max_concurrent": 1,
+            "queue_depth": 1,
+            "max_wait": 50,
+        }))
+        .unwrap();
+
+        tokio_test::block_on(async {
+            let permit = backpressure.acquire().await.unwrap();
+            // One start waits for the slot, in vain, while the next finds the queue full
+            let (queued, shed) = rocket::tokio::join!(backpressure.acquire(), async {
+                rocket::tokio::task::yield_now().await;
+                backpressure.acquire().await
+            });
+            assert!(matches!(queued, Err(Error::Overloaded { retry_after: 1 })));
+            assert!(matches!(shed, Err(Error::Overloaded { .. })));
+
+            // Queued starts get the slot once it is released
+            let (queued, _) = rocket::tokio::join!(backpressure.acquire(), async move {
+                rocket::tokio::task::yield_now().await;
+                drop(permit);
+            });
+            assert!(queued.is_ok());
+        });
+    }
+}
@@ -0,0 +1,257 @@
+// Address of the client of a request, as forwarded by trusted proxies such as the ingress
+use std::{convert::TryFrom, fmt::Display, net::IpAddr};
+
+use crate::config::CoreConfig;
+use rocket::Request;
+use serde::{Deserialize, Serialize};
+
+// Range of proxy addresses in CIDR notation, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TrustedProxy {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl TryFrom<String> for TrustedProxy {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("Invalid trusted proxy {}", value);
+        let (network, prefix) = match value.split_once('/') {
+            Some((network, prefix)) => (
+                network.parse::<IpAddr>().map_err(|_| invalid())?,
+                Some(prefix.parse::<u8>().map_err(|_| invalid())?),
+            ),
+            None => (value.parse::<IpAddr>().map_err(|_| invalid())?, None),
+        };
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            return Err(invalid());
+        }
+        Ok(TrustedProxy { network, prefix })
+    }
+}
+
+impl From<TrustedProxy> for String {
+    fn from(proxy: TrustedProxy) -> Self {
+        proxy.to_string()
+    }
+}
+
+impl Display for TrustedProxy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+impl TrustedProxy {
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+// Address in a Forwarded or X-Forwarded-For header, possibly quoted and with a port
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    let value = value.trim().trim_matches('"');
+    if let Some(bracketed) = value.strip_prefix('[') {
+        return bracketed.split(']').next()?.parse().ok();
+    }
+    value.parse().ok().or_else(|| {
+        // IPv4 address with a port
+        let (ip, _) = value.split_once(':')?;
+        ip.parse().ok()
+    })
+}
+
+// Header the trusted proxies set. Only that one is read, as proxies pass on the other one as
+// sent by the client.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    Forwarded,
+}
+
+impl ForwardedHeader {
+    fn name(self) -> &'static str {
+        match self {
+            ForwardedHeader::XForwardedFor => "X-Forwarded-For",
+            ForwardedHeader::Forwarded => "Forwarded",
+        }
+    }
+}
+
+// Addresses the request was forwarded for, from the client to the last proxy
+fn forwarded_chain<'a>(
+    header: ForwardedHeader,
+    values: impl Iterator<Item = &'a str>,
+) -> Vec<Option<IpAddr>> {
+    let elements = values.flat_map(|value| value.split(','));
+    match header {
+        ForwardedHeader::Forwarded => elements
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.split_once('=')?;
+                    if key.trim().eq_ignore_ascii_case("for") {
+                        parse_forwarded_ip(value)
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect(),
+        ForwardedHeader::XForwardedFor => elements.map(parse_forwarded_ip).collect(),
+    }
+}
+
+// Walk back from the connecting peer through trusted proxies, up to the first address not
+// belonging to one. Forwarding headers are ignored when the peer isn't a trusted proxy.
+fn resolve(peer: IpAddr, chain: Vec<Option<IpAddr>>, trusted_proxies: &[TrustedProxy]) -> IpAddr {
+    let trusted = |ip: IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+    let mut client = peer;
+    for forwarded in chain.into_iter().rev() {
+        if !trusted(client) {
+            break;
+        }
+        match forwarded {
+            Some(ip) => client = ip,
+            // Obfuscated or invalid addresses end the chain at the last known proxy
+            None => break,
+        }
+    }
+    client
+}
+
+struct ClientIp(Option<IpAddr>);
+
+// Address of the client of a request, using the trusted proxies of the global configuration
+pub fn client_ip(request: &Request<'_>) -> Option<IpAddr> {
+    request
+        .local_cache(|| {
+            let (trusted_proxies, header) = request
+                .rocket()
+                .state::<CoreConfig>()
+                .map(|config| (config.trusted_proxies(), config.forwarded_header()))
+                .unwrap_or_default();
+            let chain = forwarded_chain(header, request.headers().get(header.name()));
+            ClientIp(
+                request
+                    .remote()
+                    .map(|remote| resolve(remote.ip(), chain, trusted_proxies)),
+            )
+        })
+        .0
+}
+
+rocket::tokio::task_local! {
+    pub static CLIENT_IP: Option<IpAddr>;
+}
+
+// Client address of the request currently being handled, if any
+pub fn current_client_ip() -> Option<IpAddr> {
+    CLIENT_IP.try_with(|ip| *ip).ok().flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::TryFrom, net::IpAddr};
+
+    use super::{forwarded_chain, parse_forwarded_ip, resolve, ForwardedHeader, TrustedProxy};
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_trusted_proxy() {
+        let proxy = TrustedProxy::try_from("10.0.0.0/8".to_string()).unwrap();
+        assert!(proxy.contains(ip("10.1.2.3")));
+        assert!(!proxy.contains(ip("11.1.2.3")));
+        assert!(!proxy.contains(ip("::1")));
+
+        let proxy = TrustedProxy::try_from("fd00::/8".to_string()).unwrap();
+        assert!(proxy.contains(ip("fd12::1")));
+        assert!(!proxy.contains(ip("fe80::1")));
+
+        let proxy = TrustedProxy::try_from("127.0.0.1".to_string()).unwrap();
+        assert_eq!(proxy.to_string(), "127.0.0.1/32");
+        assert!(TrustedProxy::try_from("0.0.0.0/0".to_string())
+            .unwrap()
+            .contains(ip("1.2.3.4")));
+
+        assert!(TrustedProxy::try_from("10.0.0.0/33".to_string()).is_err());
+        assert!(TrustedProxy::try_from("proxy".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_forwarded_ip() {
+        assert_eq!(parse_forwarded_ip(" 192.0.2.60"), Some(ip("192.0.2.60")));
+        assert_eq!(
+            parse_forwarded_ip("192.0.2.60:4711"),
+            Some(ip("192.0.2.60"))
+        );
+        assert_eq!(
+            parse_forwarded_ip("\"[2001:db8:cafe::17]:4711\""),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(parse_forwarded_ip("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_forwarded_ip("unknown"), None);
+    }
+
+    #[test]
+    fn test_forwarded_chain() {
+        let values = vec![
+            "for=192.0.2.60;proto=http, for=\"[2001:db8::17]\"",
+            "for=unknown",
+        ];
+        assert_eq!(
+            forwarded_chain(ForwardedHeader::Forwarded, values.into_iter()),
+            vec![Some(ip("192.0.2.60")), Some(ip("2001:db8::17")), None]
+        );
+        let values = vec!["192.0.2.1, 198.51.100.7", "10.0.0.2"];
+        assert_eq!(
+            forwarded_chain(ForwardedHeader::XForwardedFor, values.into_iter()),
+            vec![
+                Some(ip("192.0.2.1")),
+                Some(ip("198.51.100.7")),
+                Some(ip("10.0.0.2"))
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve() {
+        let proxies = vec![TrustedProxy::try_from("10.0.0.0/8".to_string()).unwrap()];
+        let chain = vec![
+            Some(ip("192.0.2.1")),
+            Some(ip("198.51.100.7")),
+            Some(ip("10.0.0.2")),
+        ];
+
+        // Headers of untrusted peers are ignored
+        assert_eq!(
+            resolve(ip("203.0.113.9"), chain.clone(), &proxies),
+            ip("203.0.113.9")
+        );
+        // Addresses added before the first untrusted hop can be spoofed by the client
+        assert_eq!(resolve(ip("10.0.0.1"), chain, &proxies), ip("198.51.100.7"));
+        assert_eq!(
+            resolve(ip("10.0.0.1"), vec![None, Some(ip("10.0.0.2"))], &proxies),
+            ip("10.0.0.2")
+        );
+        assert_eq!(resolve(ip("10.0.0.1"), vec![], &proxies), ip("10.0.0.1"));
+    }
+}
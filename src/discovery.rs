@@ -0,0 +1,326 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    config::CoreConfig,
+    error::Error,
+    methods::{Method, Tag},
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Orbit, Rocket,
+};
+use serde::{Deserialize, Serialize, Serializer};
+use trust_dns_resolver::TokioAsyncResolver;
+
+const SERVICE_ACCOUNT_TOKEN: &str = "/var/run/secrets/kubernetes.io/serviceaccount/token";
+const SERVICE_ACCOUNT_CA: &str = "/var/run/secrets/kubernetes.io/serviceaccount/ca.crt";
+
+fn default_api_url() -> String {
+    "https://kubernetes.default.svc".into()
+}
+
+fn default_token_file() -> String {
+    SERVICE_ACCOUNT_TOKEN.into()
+}
+
+fn default_scheme() -> String {
+    "http".into()
+}
+
+fn default_refresh_interval() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum DiscoverySource {
+    // SRV record listing the plugin instances
+    Srv {
+        name: String,
+    },
+    // Ready pods matching a label selector, found with the service account of core
+    Kubernetes {
+        namespace: String,
+        selector: String,
+        port: u16,
+        #[serde(default = "default_api_url")]
+        api_url: String,
+        #[serde(default = "default_token_file")]
+        token_file: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscoveryConfig {
+    #[serde(flatten)]
+    source: DiscoverySource,
+    #[serde(default = "default_scheme")]
+    scheme: String,
+    // Interval in seconds at which the instances are looked up again
+    #[serde(default = "default_refresh_interval")]
+    refresh_interval: u64,
+}
+
+// Plugin instances found through service discovery, shared between clones of a method
+#[derive(Debug, Clone, Deserialize)]
+#[serde(from = "DiscoveryConfig")]
+pub struct Discovery {
+    source: DiscoverySource,
+    scheme: String,
+    refresh_interval: Duration,
+    instances: Arc<RwLock<Vec<String>>>,
+    next: Arc<AtomicUsize>,
+}
+
+// Discovery as configured, with the instances currently found
+#[derive(Serialize)]
+struct DiscoveryState<'a> {
+    #[serde(flatten)]
+    source: &'a DiscoverySource,
+    scheme: &'a str,
+    refresh_interval: u64,
+    instances: Vec<String>,
+}
+
+impl Serialize for Discovery {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        DiscoveryState {
+            source: &self.source,
+            scheme: &self.scheme,
+            refresh_interval: self.refresh_interval.as_secs(),
+            instances: self.instances.read().unwrap().clone(),
+        }
+        .serialize(serializer)
+    }
+}
+
+impl From<DiscoveryConfig> for Discovery {
+    fn from(config: DiscoveryConfig) -> Self {
+        Discovery {
+            source: config.source,
+            scheme: config.scheme,
+            refresh_interval: Duration::from_secs(config.refresh_interval),
+            instances: Arc::new(RwLock::new(vec![])),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct PodCondition {
+    #[serde(rename = "type")]
+    condition_type: String,
+    status: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PodStatus {
+    pod_ip: Option<String>,
+    #[serde(default)]
+    conditions: Vec<PodCondition>,
+}
+
+#[derive(Deserialize)]
+struct Pod {
+    status: PodStatus,
+}
+
+#[derive(Deserialize)]
+struct PodList {
+    items: Vec<Pod>,
+}
+
+impl Discovery {
+    // Url of one of the instances, round robin, or the fallback while none are known
+    pub fn url(&self, fallback: &str) -> String {
+        let instances = self.instances.read().unwrap();
+        if instances.is_empty() {
+            return fallback.to_string();
+        }
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        instances[next % instances.len()].clone()
+    }
+
+    async fn resolve(&self) -> Result<Vec<String>, Error> {
+        match &self.source {
+            DiscoverySource::Srv { name } => {
+                let resolver = TokioAsyncResolver::tokio_from_system_conf()
+                    .map_err(|e| Error::Discovery(e.to_string()))?;
+                let lookup = resolver
+                    .srv_lookup(name.as_str())
+                    .await
+                    .map_err(|e| Error::Discovery(e.to_string()))?;
+
+                // Only use the most preferred instances, as intended with SRV priorities
+                let priority = lookup.iter().map(|srv| srv.priority()).min();
+                Ok(lookup
+                    .iter()
+                    .filter(|srv| Some(srv.priority()) == priority)
+                    .map(|srv| {
+                        format!(
+                            "{}://{}:{}",
+                            self.scheme,
+                            srv.target().to_utf8().trim_end_matches('.'),
+                            srv.port()
+                        )
+                    })
+                    .collect())
+            }
+            DiscoverySource::Kubernetes {
+                namespace,
+                selector,
+                port,
+                api_url,
+                token_file,
+            } => {
+                let token = std::fs::read_to_string(token_file)
+                    .map_err(|e| Error::Discovery(e.to_string()))?;
+                let mut client = reqwest::Client::builder().timeout(Duration::from_secs(5));
+                if let Ok(ca) = std::fs::read(SERVICE_ACCOUNT_CA) {
+                    client = client.add_root_certificate(reqwest::Certificate::from_pem(&ca)?);
+                }
+                let pods = client
+                    .build()?
+                    .get(format!("{}/api/v1/namespaces/{}/pods", api_url, namespace))
+                    .query(&[("labelSelector", selector)])
+                    .bearer_auth(token.trim_end())
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<PodList>()
+                    .await?;
+                Ok(pods
+                    .items
+                    .into_iter()
+                    .filter(|pod| {
+                        pod.status
+                            .conditions
+                            .iter()
+                            .any(|c| c.condition_type == "Ready" && c.status == "True")
+                    })
+                    .filter_map(|pod| pod.status.pod_ip)
+                    .map(|ip| format!("{}://{}:{}", self.scheme, ip, port))
+                    .collect())
+            }
+        }
+    }
+
+    fn refresh(self, tag: Tag) {
+        rocket::tokio::spawn(async move {
+            loop {
+                match self.resolve().await {
+                    Ok(instances) => {
+                        if instances.is_empty() {
+                            log::warn!("No instances found for plugin {}", tag);
+                        }
+                        *self.instances.write().unwrap() = instances;
+                    }
+                    Err(e) => log::error!("Could not discover instances of plugin {}: {}", tag, e),
+                }
+                rocket::tokio::time::sleep(self.refresh_interval).await;
+            }
+        });
+    }
+}
+
+pub struct DiscoveryFairing;
+
+#[rocket::async_trait]
+impl Fairing for DiscoveryFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Plugin discovery",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(config) = rocket.state::<CoreConfig>() {
+            for config in config.all_tenants() {
+                for method in config.auth_methods.values() {
+                    if let Some(discovery) = method.discovery() {
+                        discovery.clone().refresh(method.tag().clone());
+                    }
+                }
+                for method in config.comm_methods.values() {
+                    if let Some(discovery) = method.discovery() {
+                        discovery.clone().refresh(method.tag().clone());
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::MockServer;
+    use rocket::figment::{providers::Serialized, Figment};
+    use serde_json::json;
+
+    use super::Discovery;
+
+    #[test]
+    fn test_kubernetes_discovery() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/api/v1/namespaces/plugins/pods")
+                .method(httpmock::Method::GET)
+                .query_param("labelSelector", "app=auth-irma")
+                .header("Authorization", "Bearer k8s_token");
+            then.status(200).json_body(json!({"items": [
+                {"status": {"podIP": "10.0.0.1", "conditions": [{"type": "Ready", "status": "True"}]}},
+                {"status": {"podIP": "10.0.0.2", "conditions": [{"type": "Ready", "status": "False"}]}},
+                {"status": {}},
+            ]}));
+        });
+
+        let token_file = std::env::temp_dir().join("id-contact-core-test-k8s-token");
+        std::fs::write(&token_file, "k8s_token\n").unwrap();
+
+        let discovery = Figment::from(Serialized::defaults(json!({
+            "type": "kubernetes",
+            "namespace": "plugins",
+            "selector": "app=auth-irma",
+            "port": 8000,
+            "api_url": server.base_url(),
+            "token_file": token_file,
+        })))
+        .extract::<Discovery>()
+        .unwrap();
+
+        let instances = tokio_test::block_on(discovery.resolve()).unwrap();
+        assert_eq!(instances, vec!["http://10.0.0.1:8000"]);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let discovery = Figment::from(Serialized::defaults(json!({
+            "type": "srv",
+            "name": "_http._tcp.auth-irma.plugins.svc.cluster.local",
+        })))
+        .extract::<Discovery>()
+        .unwrap();
+        assert_eq!(
+            discovery.url("http://auth-irma:8000"),
+            "http://auth-irma:8000"
+        );
+
+        // Clones of a method share the discovered instances
+        let clone = discovery.clone();
+        *discovery.instances.write().unwrap() =
+            vec!["http://10.0.0.1:8000".into(), "http://10.0.0.2:8000".into()];
+        assert_eq!(clone.url("http://auth-irma:8000"), "http://10.0.0.1:8000");
+        assert_eq!(
+            discovery.url("http://auth-irma:8000"),
+            "http://10.0.0.2:8000"
+        );
+    }
+}
@@ -1,15 +1,122 @@
-use std::{error::Error as StdError, fmt::Display};
+use std::{error::Error as StdError, fmt::Display, io::Cursor};
+
+use crate::{
+    errorpage::{prefers_html, render},
+    logging::request_id,
+    session::AuthFailure,
+};
+use rocket::{
+    http::{ContentType, Status},
+    response::{content::Html, Redirect, Responder},
+    Request, Response,
+};
+use serde::Serialize;
 
 #[derive(Debug)]
 pub enum Error {
     NoSuchMethod(String),
     NoSuchPurpose(String),
+    NoSuchSession(String),
+    SignatureRequired(String),
+    PurposeNotAllowed(String),
+    RequestorNotAllowed { requestor: String, purpose: String },
+    QuotaExceeded { limit: u64, retry_after: u64 },
+    CapacityExceeded { purpose: String, retry_after: u64 },
+    Overloaded { retry_after: u64 },
+    UrlstateTooLarge(usize),
+    ClientUrlTooLong(usize),
+    AdditionalComm(String),
+    AuthFailed(AuthFailure),
+    Discovery(String),
+    DeadLetter(String),
+    RequestorStore(String),
+    TelTokensExhausted,
     Reqwest(reqwest::Error),
     BadRequest,
     Jwt(josekit::JoseError),
     Json(serde_json::Error),
 }
 
+impl Error {
+    // Whether a plugin could not be reached or failed on its side, rather than rejecting the request
+    pub fn is_plugin_unavailable(&self) -> bool {
+        match self {
+            Error::Reqwest(e) => {
+                e.is_timeout()
+                    || e.is_connect()
+                    || matches!(e.status(), Some(status) if status.is_server_error())
+            }
+            Error::Discovery(_) => true,
+            _ => false,
+        }
+    }
+
+    // Code appended to error urls, telling the page what went wrong without any details
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::NoSuchMethod(_)
+            | Error::NoSuchPurpose(_)
+            | Error::ClientUrlTooLong(_)
+            | Error::AdditionalComm(_)
+            | Error::BadRequest => "bad_request",
+            Error::NoSuchSession(_) | Error::Jwt(_) => "session_expired",
+            Error::SignatureRequired(_)
+            | Error::PurposeNotAllowed(_)
+            | Error::RequestorNotAllowed { .. } => "not_allowed",
+            Error::QuotaExceeded { .. } => "quota_exceeded",
+            Error::CapacityExceeded { .. } => "capacity_exceeded",
+            Error::Overloaded { .. } | Error::TelTokensExhausted => "overloaded",
+            Error::AuthFailed(AuthFailure::Cancelled) => "auth_cancelled",
+            Error::AuthFailed(AuthFailure::Failed) => "auth_failed",
+            Error::Discovery(_) | Error::Reqwest(_) => "plugin_error",
+            Error::UrlstateTooLarge(_)
+            | Error::DeadLetter(_)
+            | Error::RequestorStore(_)
+            | Error::Json(_) => "internal_error",
+        }
+    }
+
+    // Send browsers to the error url of the purpose instead of answering with a bare error
+    pub fn redirect_to(self, error_url: Option<&str>) -> ErrorRedirect {
+        ErrorRedirect {
+            error: self,
+            error_url: error_url.map(|url| url.to_string()),
+        }
+    }
+}
+
+// Error of a flow through the browser, redirecting to an error url if the purpose has one
+#[derive(Debug)]
+pub struct ErrorRedirect {
+    error: Error,
+    error_url: Option<String>,
+}
+
+impl From<Error> for ErrorRedirect {
+    fn from(error: Error) -> ErrorRedirect {
+        error.redirect_to(None)
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for ErrorRedirect {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        match self.error_url {
+            Some(error_url) => {
+                log::warn!("Redirecting to error url after error: {}", self.error);
+                let separator = if error_url.contains('?') { '&' } else { '?' };
+                Redirect::to(format!(
+                    "{}{}reason={}",
+                    error_url,
+                    separator,
+                    self.error.reason()
+                ))
+                .respond_to(request)
+            }
+            None => self.error.respond_to(request),
+        }
+    }
+}
+
 impl From<reqwest::Error> for Error {
     fn from(e: reqwest::Error) -> Error {
         Error::Reqwest(e)
@@ -28,23 +135,124 @@ impl From<serde_json::Error> for Error {
     }
 }
 
-impl<'r, 'o: 'r> rocket::response::Responder<'r, 'o> for Error {
-    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'o> {
+// Body of error responses, in the problem details format of RFC 7807. Only the status is
+// described, so internal details never reach clients.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    request_id: String,
+}
+
+impl Problem {
+    pub fn new(status: Status, request: &Request<'_>) -> Self {
+        Problem {
+            problem_type: "about:blank",
+            title: status.reason().unwrap_or("Unknown Error"),
+            status: status.code,
+            request_id: request_id(request).to_string(),
+        }
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for Problem {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
+        let status = Status::from_code(self.status).unwrap_or(Status::InternalServerError);
+        // Users ending up here in their browser get a page instead
+        if prefers_html(request) {
+            return (status, Html(render(status, request))).respond_to(request);
+        }
+
+        let body = serde_json::to_string(&self).map_err(|_| Status::InternalServerError)?;
+        Response::build()
+            .status(status)
+            .header(ContentType::new("application", "problem+json"))
+            .sized_body(body.len(), Cursor::new(body))
+            .ok()
+    }
+}
+
+impl<'r, 'o: 'r> Responder<'r, 'o> for Error {
+    fn respond_to(self, request: &'r Request<'_>) -> rocket::response::Result<'o> {
         match self {
             Error::NoSuchMethod(m) => {
-                let bad_request = rocket::response::status::BadRequest::<()>(None);
                 log::error!("Unknown method {}", m);
-                bad_request.respond_to(request)
+                Problem::new(Status::BadRequest, request).respond_to(request)
             }
             Error::NoSuchPurpose(m) => {
-                let bad_request = rocket::response::status::BadRequest::<()>(None);
                 log::error!("Unknown purpose {}", m);
-                bad_request.respond_to(request)
+                Problem::new(Status::BadRequest, request).respond_to(request)
             }
-            Error::BadRequest => {
-                let bad_request = rocket::response::status::BadRequest::<()>(None);
-                bad_request.respond_to(request)
+            Error::NoSuchSession(m) => {
+                log::error!("Unknown session {}", m);
+                Problem::new(Status::NotFound, request).respond_to(request)
             }
+            Error::SignatureRequired(m) => {
+                log::warn!("Unsigned start request for purpose {}", m);
+                Problem::new(Status::Forbidden, request).respond_to(request)
+            }
+            Error::PurposeNotAllowed(m) => {
+                log::warn!("Api key not allowed for purpose {}", m);
+                Problem::new(Status::Forbidden, request).respond_to(request)
+            }
+            Error::RequestorNotAllowed { requestor, purpose } => {
+                log::warn!(
+                    "Requestor {} not allowed for purpose {}",
+                    requestor,
+                    purpose
+                );
+                Problem::new(Status::Forbidden, request).respond_to(request)
+            }
+            Error::QuotaExceeded { limit, retry_after } => {
+                log::warn!("Requestor exceeded quota of {} starts", limit);
+                Response::build_from(
+                    Problem::new(Status::TooManyRequests, request).respond_to(request)?,
+                )
+                .raw_header("Retry-After", retry_after.to_string())
+                .raw_header("X-RateLimit-Limit", limit.to_string())
+                .raw_header("X-RateLimit-Remaining", "0")
+                .raw_header("X-RateLimit-Reset", retry_after.to_string())
+                .ok()
+            }
+            Error::CapacityExceeded {
+                purpose,
+                retry_after,
+            } => {
+                log::warn!("Session cap of purpose {} reached", purpose);
+                Response::build_from(
+                    Problem::new(Status::ServiceUnavailable, request).respond_to(request)?,
+                )
+                .raw_header("Retry-After", retry_after.to_string())
+                .ok()
+            }
+            Error::Overloaded { retry_after } => Response::build_from(
+                Problem::new(Status::ServiceUnavailable, request).respond_to(request)?,
+            )
+            .raw_header("Retry-After", retry_after.to_string())
+            .ok(),
+            Error::TelTokensExhausted => {
+                log::error!("No unused tel token left to issue");
+                Problem::new(Status::ServiceUnavailable, request).respond_to(request)
+            }
+            Error::AuthFailed(reason) => {
+                log::info!("Authentication ended without result: {:?}", reason);
+                Problem::new(Status::BadRequest, request).respond_to(request)
+            }
+            Error::ClientUrlTooLong(size) => {
+                log::warn!("Client url with auth result too long: {} bytes", size);
+                Problem::new(Status::BadRequest, request).respond_to(request)
+            }
+            Error::AdditionalComm(m) => {
+                log::warn!(
+                    "Full session for purpose {} with additional comm methods",
+                    m
+                );
+                Problem::new(Status::BadRequest, request).respond_to(request)
+            }
+            Error::BadRequest => Problem::new(Status::BadRequest, request).respond_to(request),
+            // Logged and answered by the 500 catcher
             _ => {
                 let debug_error = rocket::response::Debug::from(self);
                 debug_error.respond_to(request)
@@ -58,6 +266,43 @@ impl Display for Error {
         match self {
             Error::NoSuchMethod(m) => f.write_fmt(format_args!("No such method: {}", m)),
             Error::NoSuchPurpose(m) => f.write_fmt(format_args!("No such purpose: {}", m)),
+            Error::NoSuchSession(m) => f.write_fmt(format_args!("No such session: {}", m)),
+            Error::SignatureRequired(m) => f.write_fmt(format_args!(
+                "Signed start request required for purpose: {}",
+                m
+            )),
+            Error::PurposeNotAllowed(m) => {
+                f.write_fmt(format_args!("Api key not allowed for purpose: {}", m))
+            }
+            Error::RequestorNotAllowed { requestor, purpose } => f.write_fmt(format_args!(
+                "Requestor {} not allowed for purpose: {}",
+                requestor, purpose
+            )),
+            Error::QuotaExceeded { limit, .. } => {
+                f.write_fmt(format_args!("Quota of {} starts exceeded", limit))
+            }
+            Error::CapacityExceeded { purpose, .. } => {
+                f.write_fmt(format_args!("Session cap of purpose {} reached", purpose))
+            }
+            Error::Overloaded { .. } => f.write_str("Too many session starts in progress"),
+            Error::UrlstateTooLarge(size) => {
+                f.write_fmt(format_args!("Url state too large: {} bytes", size))
+            }
+            Error::ClientUrlTooLong(size) => {
+                f.write_fmt(format_args!("Client url too long: {} bytes", size))
+            }
+            Error::AdditionalComm(m) => f.write_fmt(format_args!(
+                "Purpose {} starts additional comm methods, which full sessions can't deliver results to",
+                m
+            )),
+            Error::AuthFailed(AuthFailure::Cancelled) => f.write_str("Authentication cancelled"),
+            Error::AuthFailed(AuthFailure::Failed) => f.write_str("Authentication failed"),
+            Error::Discovery(e) => f.write_fmt(format_args!("Service discovery failed: {}", e)),
+            Error::DeadLetter(e) => f.write_fmt(format_args!("Dead letter store failed: {}", e)),
+            Error::RequestorStore(e) => {
+                f.write_fmt(format_args!("Could not store managed requestors: {}", e))
+            }
+            Error::TelTokensExhausted => f.write_str("No unused tel token left to issue"),
             Error::Reqwest(e) => e.fmt(f),
             Error::Jwt(e) => e.fmt(f),
             Error::Json(e) => e.fmt(f),
@@ -76,3 +321,182 @@ impl StdError for Error {
         }
     }
 }
+
+// A single problem in the configuration. These never include configured values, as those
+// could contain private keys.
+#[derive(Debug)]
+pub enum ConfigError {
+    // Only the field a problem concerns is kept, as serde messages can quote configured values
+    Json(Option<String>),
+    // Problem reported while reading the configuration files, already without values
+    Figment(String),
+    Tenant(String, Box<ConfigError>),
+    SecretFile(String, std::io::Error),
+    KeyFileType(String, serde_json::Error),
+    KeyType(String),
+    InternalSecret,
+    InternalKey(josekit::JoseError),
+    RequestorKey(String),
+    SigningKey(String),
+    ExternalSigner(String),
+    SigningKeyChoice,
+    InvalidAuthMethod(String),
+    InvalidCommMethod(String),
+    InvalidAuthFallback(String),
+    InvalidAuthChain(String),
+    MissingShimUrl { scheme: String, auth_method: String },
+    UnknownAttribute(String),
+    DuplicatePurposeTag(String),
+    PluginClient(String, String),
+    IdGenerator(String),
+    UnknownRoute(String),
+    ManagedRequestors(String),
+    SharedPath(String),
+    TelTokenDigits(usize),
+    CleanupInterval,
+    Logging,
+    Vault,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Json(Some(field)) => {
+                f.write_fmt(format_args!("Invalid configuration field {}", field))
+            }
+            ConfigError::Json(None) => f.write_str("Invalid configuration value"),
+            ConfigError::Figment(line) => f.write_str(line),
+            ConfigError::Tenant(tenant, e) => {
+                f.write_fmt(format_args!("In tenant {}: {}", tenant, e))
+            }
+            ConfigError::SecretFile(path, e) => {
+                f.write_fmt(format_args!("Could not read secret file {}: {}", path, e))
+            }
+            ConfigError::KeyFileType(path, e) => f.write_fmt(format_args!(
+                "Invalid key type for key file {}: {}",
+                path, e
+            )),
+            ConfigError::KeyType(key_type) => f.write_fmt(format_args!(
+                "Unsupported key type {}, keys must be RSA or EC",
+                key_type
+            )),
+            ConfigError::InternalSecret => {
+                f.write_str("Exactly one of internal_secret and internal_secret_file must be set")
+            }
+            ConfigError::InternalKey(e) => f.write_fmt(format_args!(
+                "Could not generate signer from internal secret: {}",
+                e
+            )),
+            ConfigError::RequestorKey(requestor) => f.write_fmt(format_args!(
+                "Could not parse requestor key for requestor {}",
+                requestor
+            )),
+            ConfigError::SigningKey(e) => f.write_fmt(format_args!(
+                "Could not generate signer from core private key: {}",
+                e
+            )),
+            ConfigError::ExternalSigner(e) => {
+                f.write_fmt(format_args!("Could not set up external signer: {}", e))
+            }
+            ConfigError::SigningKeyChoice => {
+                f.write_str("Exactly one of ui_signing_privkey and ui_signing_provider must be set")
+            }
+            ConfigError::InvalidAuthMethod(purpose) => {
+                f.write_fmt(format_args!("Invalid auth method in purpose {}", purpose))
+            }
+            ConfigError::InvalidCommMethod(purpose) => {
+                f.write_fmt(format_args!("Invalid comm method in purpose {}", purpose))
+            }
+            ConfigError::InvalidAuthFallback(purpose) => {
+                f.write_fmt(format_args!("Invalid auth fallback in purpose {}", purpose))
+            }
+            ConfigError::InvalidAuthChain(purpose) => {
+                f.write_fmt(format_args!("Invalid auth chain in purpose {}", purpose))
+            }
+            ConfigError::MissingShimUrl {
+                scheme,
+                auth_method,
+            } => f.write_fmt(format_args!(
+                "No UI url for {} continuations of auth method {}",
+                scheme, auth_method
+            )),
+            ConfigError::UnknownAttribute(purpose) => {
+                f.write_fmt(format_args!("Unknown attribute in purpose {}", purpose))
+            }
+            ConfigError::DuplicatePurposeTag(tag) => f.write_fmt(format_args!(
+                "Purpose tag or alias {} is used more than once",
+                tag
+            )),
+            ConfigError::PluginClient(tag, e) => f.write_fmt(format_args!(
+                "Could not set up http client for method {}: {}",
+                tag, e
+            )),
+            ConfigError::IdGenerator(e) => {
+                f.write_fmt(format_args!("Invalid id generator configuration: {}", e))
+            }
+            ConfigError::UnknownRoute(route) => {
+                f.write_fmt(format_args!("Unknown route {} in disabled_routes", route))
+            }
+            ConfigError::ManagedRequestors(e) => {
+                f.write_fmt(format_args!("Could not load managed requestors: {}", e))
+            }
+            ConfigError::SharedPath(option) => f.write_fmt(format_args!(
+                "Path of {} is also used by another tenant or the global configuration",
+                option
+            )),
+            ConfigError::TelTokenDigits(digits) => f.write_fmt(format_args!(
+                "Tel tokens must have 4 to 12 digits, not {}",
+                digits
+            )),
+            ConfigError::CleanupInterval => {
+                f.write_str("cleanup_interval must be at least one second")
+            }
+            ConfigError::Logging => f.write_str("Failure to parse logging configuration"),
+            ConfigError::Vault => f.write_str("Could not load secrets from vault"),
+        }
+    }
+}
+
+impl StdError for ConfigError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ConfigError::Tenant(_, e) => Some(e.as_ref()),
+            ConfigError::SecretFile(_, e) => Some(e),
+            ConfigError::KeyFileType(_, e) => Some(e),
+            ConfigError::InternalKey(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// All problems found in the configuration, one per line
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl ConfigErrors {
+    pub fn errors(&self) -> &[ConfigError] {
+        &self.0
+    }
+}
+
+impl From<serde_json::Error> for ConfigErrors {
+    fn from(e: serde_json::Error) -> ConfigErrors {
+        // Field names are quoted in backticks, unlike the values serde may quote
+        let message = e.to_string();
+        let field = ["missing field `", "unknown field `", "duplicate field `"]
+            .iter()
+            .find_map(|prefix| message.strip_prefix(prefix))
+            .and_then(|rest| rest.split('`').next())
+            .map(String::from);
+        ConfigErrors(vec![ConfigError::Json(field)])
+    }
+}
+
+impl Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lines: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+impl StdError for ConfigErrors {}
@@ -0,0 +1,85 @@
+// Error pages for users ending up at core in their browser, e.g. through the shim or a redirect
+use crate::{config::CoreConfig, logging::request_id, select::escape};
+use rocket::{http::Status, Request};
+
+// Placeholders {lang}, {status}, {title}, {message} and {request_id} are filled in, the same
+// as in a configured error_page_template
+const DEFAULT_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html lang="{lang}">
+<head>
+  <meta charset="utf-8"/>
+  <meta name="viewport" content="width=device-width, initial-scale=1">
+  <title>{title}</title>
+  <link rel="stylesheet" type="text/css" href="/static/base.css" media="all"/>
+</head>
+<body>
+  <main>
+    <h1>{title}</h1>
+    <p>{message}</p>
+    <p><small>{status} {request_id}</small></p>
+  </main>
+</body>
+</html>
+"#;
+
+// Whether the client would rather have a page than a problem document, as browsers do
+pub fn prefers_html(request: &Request<'_>) -> bool {
+    matches!(request.accept(), Some(accept) if accept.preferred().media_type().is_html())
+}
+
+// Pages are in Dutch unless English is preferred
+pub fn language(request: &Request<'_>) -> &'static str {
+    let preferred = request
+        .headers()
+        .get_one("Accept-Language")
+        .and_then(|header| header.split(',').next())
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    if preferred.starts_with("en") {
+        "en"
+    } else {
+        "nl"
+    }
+}
+
+fn text(status: Status, language: &str) -> (&'static str, &'static str) {
+    match (status.code, language) {
+        (404, "en") => (
+            "Page not found",
+            "This link is invalid or has expired. Please start again from the website you came from.",
+        ),
+        (404, _) => (
+            "Pagina niet gevonden",
+            "Deze link is ongeldig of verlopen. Begin opnieuw vanaf de website waar u vandaan kwam.",
+        ),
+        (400..=499, "en") => (
+            "Request not possible",
+            "Your request could not be handled. Please start again from the website you came from.",
+        ),
+        (400..=499, _) => (
+            "Verzoek niet mogelijk",
+            "Uw verzoek kon niet worden verwerkt. Begin opnieuw vanaf de website waar u vandaan kwam.",
+        ),
+        (_, "en") => ("Something went wrong", "Please try again later."),
+        (_, _) => ("Er ging iets mis", "Probeer het later opnieuw."),
+    }
+}
+
+// The template of the global configuration is used, as errors can occur before the tenant of
+// a request is known
+pub fn render(status: Status, request: &Request<'_>) -> String {
+    let language = language(request);
+    let (title, message) = text(status, language);
+    let template = request
+        .rocket()
+        .state::<CoreConfig>()
+        .and_then(|config| config.error_page_template())
+        .unwrap_or(DEFAULT_TEMPLATE);
+    template
+        .replace("{lang}", language)
+        .replace("{status}", &status.code.to_string())
+        .replace("{title}", title)
+        .replace("{message}", message)
+        .replace("{request_id}", &escape(request_id(request)))
+}
@@ -0,0 +1,394 @@
+// Session lifecycle events published as CloudEvents to a message broker, for analytics
+use std::{
+    error::Error as StdError,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{config::CoreConfig, corekeys::NotificationSigner, session::LifecycleEvent};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+        sync::mpsc,
+        time::timeout,
+    },
+    Orbit, Rocket,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+const PUBLISH_TIMEOUT: Duration = Duration::from_secs(5);
+// Events waiting to be published, beyond which new ones are dropped
+const EVENT_BACKLOG: usize = 10_000;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "broker", rename_all = "lowercase")]
+pub enum EventPublisherConfig {
+    // Address of a NATS server as host:port
+    Nats {
+        address: String,
+        subject: String,
+    },
+    // Kafka is reached through a REST proxy, as core has no native client
+    Kafka {
+        rest_proxy_url: String,
+        topic: String,
+    },
+}
+
+// Time as in the time attribute of CloudEvents, from the civil date algorithm of
+// http://howardhinnant.github.io/date_algorithms.html
+fn rfc3339(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn cloud_event(source: &str, event: &LifecycleEvent, time: SystemTime) -> Value {
+    let mut data = serde_json::to_value(&event.event).unwrap_or_default();
+    let event_type = data
+        .as_object_mut()
+        .and_then(|data| data.remove("event"))
+        .and_then(|event_type| event_type.as_str().map(|t| t.to_string()))
+        .unwrap_or_default();
+    let mut cloud_event = json!({
+        "specversion": "1.0",
+        "id": format!("{}-{}", event.session_id, event.event_id),
+        "source": source,
+        "type": format!("nl.idcontact.session.{}", event_type),
+        "subject": event.session_id,
+        "time": rfc3339(time),
+        "datacontenttype": "application/json",
+        "data": data,
+    });
+    if let Some(purpose) = &event.purpose {
+        cloud_event["purpose"] = purpose.as_str().into();
+    }
+    cloud_event
+}
+
+// Consumers authenticate events by the jws extension attribute, a JWS over the rest of the event
+fn sign_event(mut event: Value, signer: &NotificationSigner) -> Result<Value, josekit::JoseError> {
+    event["jws"] = signer.sign(event.to_string().as_bytes())?.into();
+    Ok(event)
+}
+
+async fn nats_connect(address: &str) -> std::io::Result<BufReader<TcpStream>> {
+    let mut stream = BufReader::new(TcpStream::connect(address).await?);
+    let mut info = String::new();
+    stream.read_line(&mut info).await?;
+    if !info.starts_with("INFO") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Not a NATS server",
+        ));
+    }
+    stream
+        .write_all(b"CONNECT {\"verbose\":false,\"pedantic\":false}\r\n")
+        .await?;
+    Ok(stream)
+}
+
+// Publish a message, followed by a ping so its pong confirms the server processed the message
+async fn nats_publish(
+    stream: &mut BufReader<TcpStream>,
+    subject: &str,
+    payload: &str,
+) -> std::io::Result<()> {
+    stream
+        .write_all(
+            format!(
+                "PUB {} {}\r\n{}\r\nPING\r\n",
+                subject,
+                payload.len(),
+                payload
+            )
+            .as_bytes(),
+        )
+        .await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        match line.trim_end() {
+            "PONG" => return Ok(()),
+            "PING" => stream.write_all(b"PONG\r\n").await?,
+            error if error.starts_with("-ERR") => return Err(std::io::Error::other(error)),
+            _ => {}
+        }
+    }
+}
+
+struct Publisher {
+    config: EventPublisherConfig,
+    nats: Option<BufReader<TcpStream>>,
+    client: reqwest::Client,
+}
+
+impl Publisher {
+    fn new(config: EventPublisherConfig) -> Result<Self, reqwest::Error> {
+        Ok(Publisher {
+            config,
+            nats: None,
+            client: reqwest::Client::builder()
+                .timeout(PUBLISH_TIMEOUT)
+                .build()?,
+        })
+    }
+
+    async fn send(&mut self, event: &Value) -> Result<(), Box<dyn StdError + Send + Sync>> {
+        match &self.config {
+            EventPublisherConfig::Nats { address, subject } => {
+                let payload = event.to_string();
+                // The connection may have been closed since the last event, so retry once
+                if let Some(mut stream) = self.nats.take() {
+                    if let Ok(Ok(())) = timeout(
+                        PUBLISH_TIMEOUT,
+                        nats_publish(&mut stream, subject, &payload),
+                    )
+                    .await
+                    {
+                        self.nats = Some(stream);
+                        return Ok(());
+                    }
+                }
+                let mut stream = timeout(PUBLISH_TIMEOUT, nats_connect(address)).await??;
+                timeout(
+                    PUBLISH_TIMEOUT,
+                    nats_publish(&mut stream, subject, &payload),
+                )
+                .await??;
+                self.nats = Some(stream);
+            }
+            EventPublisherConfig::Kafka {
+                rest_proxy_url,
+                topic,
+            } => {
+                // Keyed by session, so the events of a session stay in order
+                self.client
+                    .post(format!(
+                        "{}/topics/{}",
+                        rest_proxy_url.trim_end_matches('/'),
+                        topic
+                    ))
+                    .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                    .json(&json!({ "records": [{ "key": event["subject"], "value": event }] }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct EventPublisherFairing;
+
+#[rocket::async_trait]
+impl Fairing for EventPublisherFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Session event publisher",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(config) = rocket.state::<CoreConfig>() {
+            for config in config.all_tenants() {
+                let publisher_config = match config.event_publisher() {
+                    Some(publisher_config) => publisher_config.clone(),
+                    None => continue,
+                };
+                let mut publisher = match Publisher::new(publisher_config) {
+                    Ok(publisher) => publisher,
+                    Err(e) => {
+                        log::error!("Could not set up event publisher: {}", e);
+                        continue;
+                    }
+                };
+                let (sender, mut receiver) = mpsc::channel(EVENT_BACKLOG);
+                config.sessions().forward_events(sender);
+                let source = config.server_url().to_string();
+                let signer = config.notification_signer();
+                rocket::tokio::spawn(async move {
+                    while let Some(event) = receiver.recv().await {
+                        let event = cloud_event(&source, &event, SystemTime::now());
+                        let event = match sign_event(event, &signer) {
+                            Ok(event) => event,
+                            Err(e) => {
+                                log::error!("Could not sign session event: {}", e);
+                                continue;
+                            }
+                        };
+                        if let Err(e) = publisher.send(&event).await {
+                            log::warn!("Could not publish session event: {}", e);
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::TryFrom,
+        sync::Arc,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use httpmock::MockServer;
+    use josekit::jws::{self, HS256};
+    use rocket::tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
+    use serde_json::{json, Value};
+
+    use super::{cloud_event, rfc3339, sign_event, EventPublisherConfig, Publisher};
+    use crate::{
+        corekeys::NotificationSigner,
+        methods::Tag,
+        session::{LifecycleEvent, SessionEvent},
+    };
+
+    fn event() -> LifecycleEvent {
+        LifecycleEvent {
+            session_id: "session".into(),
+            event_id: 1,
+            purpose: Some("report_move".into()),
+            event: SessionEvent::CommSelected {
+                comm_method: Tag::try_from("call").unwrap(),
+                success: true,
+            },
+        }
+    }
+
+    #[test]
+    fn test_cloud_event() {
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00Z");
+        assert_eq!(
+            rfc3339(UNIX_EPOCH + Duration::from_secs(951_782_400 + 3723)),
+            "2000-02-29T01:02:03Z"
+        );
+
+        let time = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(
+            cloud_event("https://core.example.com", &event(), time),
+            json!({
+                "specversion": "1.0",
+                "id": "session-1",
+                "source": "https://core.example.com",
+                "type": "nl.idcontact.session.comm_selected",
+                "subject": "session",
+                "time": "2020-09-13T12:26:40Z",
+                "datacontenttype": "application/json",
+                "purpose": "report_move",
+                "data": { "comm_method": "call", "success": true },
+            })
+        );
+    }
+
+    #[test]
+    fn test_sign_event() {
+        let signer = NotificationSigner::new(
+            Arc::new(HS256.signer_from_bytes(b"event_signing_key").unwrap()),
+            Some("core".into()),
+        );
+        let event = cloud_event("https://core.example.com", &event(), UNIX_EPOCH);
+        let signed = sign_event(event.clone(), &signer).unwrap();
+
+        let verifier = HS256.verifier_from_bytes(b"event_signing_key").unwrap();
+        let (payload, header) =
+            jws::deserialize_compact(signed["jws"].as_str().unwrap(), &verifier).unwrap();
+        assert_eq!(header.key_id(), Some("core"));
+        assert_eq!(serde_json::from_slice::<Value>(&payload).unwrap(), event);
+    }
+
+    #[test]
+    fn test_publish_kafka() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/topics/sessions")
+                .header("Content-Type", "application/vnd.kafka.json.v2+json")
+                .json_body(
+                    json!({ "records": [{ "key": "session", "value": { "subject": "session" } }] }),
+                );
+            then.status(200);
+        });
+
+        let mut publisher = Publisher::new(EventPublisherConfig::Kafka {
+            rest_proxy_url: server.base_url(),
+            topic: "sessions".into(),
+        })
+        .unwrap();
+        tokio_test::block_on(publisher.send(&json!({ "subject": "session" }))).unwrap();
+        mock.assert();
+    }
+
+    #[test]
+    fn test_publish_nats() {
+        let runtime = rocket::tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            let server = rocket::tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let mut stream = BufReader::new(stream);
+                stream.write_all(b"INFO {}\r\n").await.unwrap();
+                let mut lines = Vec::new();
+                let mut line = String::new();
+                while stream.read_line(&mut line).await.unwrap() > 0 {
+                    if line.starts_with("PUB") {
+                        let len: usize =
+                            line.trim_end().rsplit(' ').next().unwrap().parse().unwrap();
+                        let mut payload = vec![0; len + 2];
+                        stream.read_exact(&mut payload).await.unwrap();
+                        lines.push(String::from_utf8(payload).unwrap().trim_end().to_string());
+                    } else if line.starts_with("PING") {
+                        stream.write_all(b"PONG\r\n").await.unwrap();
+                        if lines.len() == 2 {
+                            return lines;
+                        }
+                    }
+                    line.clear();
+                }
+                lines
+            });
+
+            let mut publisher = Publisher::new(EventPublisherConfig::Nats {
+                address,
+                subject: "sessions".into(),
+            })
+            .unwrap();
+            publisher.send(&json!({ "id": 1 })).await.unwrap();
+            publisher.send(&json!({ "id": 2 })).await.unwrap();
+            assert_eq!(server.await.unwrap(), vec![r#"{"id":1}"#, r#"{"id":2}"#]);
+        });
+    }
+}
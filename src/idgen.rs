@@ -0,0 +1,211 @@
+// Generation of the random tokens core hands out, such as session ids and short url tokens.
+// Deployments with their own format requirements can install a generator before starting core.
+use std::{
+    convert::TryFrom,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+};
+
+use crate::error::ConfigError;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+// Session ids must be hard to guess, as they give access to session results
+const MIN_SESSION_ID_BITS: f64 = 128.0;
+
+// Length of ids that only need to be unique, such as those of requests and dead letters
+const REFERENCE_ID_LENGTH: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IdKind {
+    Session,
+    ShortUrl,
+    DeadLetter,
+    Request,
+}
+
+pub trait IdGenerator: Debug + Send + Sync {
+    // New token of the given kind, consisting of url-safe characters
+    fn generate(&self, kind: IdKind) -> String;
+}
+
+static INSTALLED: Mutex<Option<Arc<dyn IdGenerator>>> = Mutex::new(None);
+
+// Use the given generator instead of the configured one for all configurations parsed from now on
+pub fn install_id_generator(generator: impl IdGenerator + 'static) {
+    *INSTALLED.lock().unwrap() = Some(Arc::new(generator));
+}
+
+fn default_alphabet() -> String {
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789".into()
+}
+
+fn default_session_id_length() -> usize {
+    32
+}
+
+fn default_short_url_length() -> usize {
+    8
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct IdGeneratorConfig {
+    #[serde(default = "default_alphabet")]
+    alphabet: String,
+    #[serde(default = "default_session_id_length")]
+    session_id_length: usize,
+    #[serde(default = "default_short_url_length")]
+    short_url_length: usize,
+}
+
+impl Default for IdGeneratorConfig {
+    fn default() -> Self {
+        IdGeneratorConfig {
+            alphabet: default_alphabet(),
+            session_id_length: default_session_id_length(),
+            short_url_length: default_short_url_length(),
+        }
+    }
+}
+
+// Tokens of characters drawn uniformly from an alphabet, using the CSPRNG of the thread
+#[derive(Debug)]
+struct RandomIdGenerator {
+    alphabet: Vec<char>,
+    session_id_length: usize,
+    short_url_length: usize,
+}
+
+impl IdGenerator for RandomIdGenerator {
+    fn generate(&self, kind: IdKind) -> String {
+        let length = match kind {
+            IdKind::Session => self.session_id_length,
+            IdKind::ShortUrl => self.short_url_length,
+            IdKind::DeadLetter | IdKind::Request => REFERENCE_ID_LENGTH,
+        };
+        let mut rng = rand::thread_rng();
+        (0..length)
+            .map(|_| self.alphabet[rng.gen_range(0..self.alphabet.len())])
+            .collect()
+    }
+}
+
+impl TryFrom<IdGeneratorConfig> for RandomIdGenerator {
+    type Error = ConfigError;
+
+    fn try_from(config: IdGeneratorConfig) -> Result<Self, ConfigError> {
+        let mut alphabet: Vec<char> = config.alphabet.chars().collect();
+        alphabet.sort_unstable();
+        alphabet.dedup();
+        if alphabet.len() != config.alphabet.chars().count()
+            || alphabet
+                .iter()
+                .any(|c| !c.is_ascii_alphanumeric() && !"-_.~".contains(*c))
+        {
+            return Err(ConfigError::IdGenerator(
+                "alphabet must consist of distinct url-safe characters".into(),
+            ));
+        }
+        let bits = config.session_id_length as f64 * (alphabet.len() as f64).log2();
+        if bits < MIN_SESSION_ID_BITS {
+            return Err(ConfigError::IdGenerator(format!(
+                "session ids must have at least {} bits of randomness",
+                MIN_SESSION_ID_BITS
+            )));
+        }
+        if config.short_url_length == 0 {
+            return Err(ConfigError::IdGenerator(
+                "short_url_length must be positive".into(),
+            ));
+        }
+        Ok(RandomIdGenerator {
+            alphabet,
+            session_id_length: config.session_id_length,
+            short_url_length: config.short_url_length,
+        })
+    }
+}
+
+// Generator used by a configuration, shared between clones
+#[derive(Debug, Clone)]
+pub struct Ids(Arc<dyn IdGenerator>);
+
+impl Ids {
+    // The installed generator, or else the random one configured
+    pub fn from_config(config: IdGeneratorConfig) -> Result<Self, ConfigError> {
+        if let Some(generator) = INSTALLED.lock().unwrap().clone() {
+            return Ok(Ids(generator));
+        }
+        Ok(Ids(Arc::new(RandomIdGenerator::try_from(config)?)))
+    }
+
+    pub fn generate(&self, kind: IdKind) -> String {
+        self.0.generate(kind)
+    }
+}
+
+impl Default for Ids {
+    fn default() -> Self {
+        Ids(Arc::new(
+            RandomIdGenerator::try_from(IdGeneratorConfig::default())
+                .expect("Default id generator configuration must be valid"),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{IdGenerator, IdGeneratorConfig, IdKind, Ids, RandomIdGenerator};
+    use std::{convert::TryFrom, sync::Arc};
+
+    fn generator(config: serde_json::Value) -> Result<RandomIdGenerator, String> {
+        let config: IdGeneratorConfig = serde_json::from_value(config).unwrap();
+        RandomIdGenerator::try_from(config).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn test_random_ids() {
+        let ids = Ids::default();
+        let session_id = ids.generate(IdKind::Session);
+        assert_eq!(session_id.len(), 32);
+        assert!(session_id.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_eq!(ids.generate(IdKind::ShortUrl).len(), 8);
+        assert_eq!(ids.generate(IdKind::DeadLetter).len(), 16);
+        assert_eq!(ids.generate(IdKind::Request).len(), 16);
+        assert_ne!(session_id, ids.generate(IdKind::Session));
+
+        let hex =
+            generator(json!({"alphabet": "0123456789abcdef", "short_url_length": 6})).unwrap();
+        let session_id = hex.generate(IdKind::Session);
+        assert!(session_id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_eq!(hex.generate(IdKind::ShortUrl).len(), 6);
+
+        // Session ids too easy to guess or with unsafe characters are refused
+        assert!(
+            generator(json!({"alphabet": "0123456789abcdef", "session_id_length": 16})).is_err()
+        );
+        assert!(generator(json!({"alphabet": "ab/+0123456789"})).is_err());
+        assert!(generator(json!({"alphabet": "aa0123456789"})).is_err());
+        assert!(generator(json!({"short_url_length": 0})).is_err());
+    }
+
+    // Time-ordered ids, as a deployment could plug in for log ordering
+    #[derive(Debug)]
+    struct Sequential;
+
+    impl IdGenerator for Sequential {
+        fn generate(&self, kind: IdKind) -> String {
+            format!("{:?}-0001", kind)
+        }
+    }
+
+    #[test]
+    fn test_custom_ids() {
+        let ids = Ids(Arc::new(Sequential));
+        assert_eq!(ids.generate(IdKind::Session), "Session-0001");
+        assert_eq!(ids.generate(IdKind::ShortUrl), "ShortUrl-0001");
+        assert_eq!(ids.generate(IdKind::Request), "Request-0001");
+    }
+}
@@ -0,0 +1,176 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use crate::{config::CoreConfig, error::Error};
+use josekit::{
+    jwk::{Jwk, JwkSet},
+    jws::{JwsVerifier, ES256, RS256},
+};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Orbit, Rocket,
+};
+
+// Minimum time between refreshes triggered by an unknown key id
+const MIN_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Default)]
+struct CachedKeys {
+    fetched: Option<Instant>,
+    keys: HashMap<String, Jwk>,
+}
+
+// Public keys published at a JWKS endpoint, cached and shared between clones
+#[derive(Debug, Clone)]
+pub struct Jwks {
+    url: String,
+    refresh_interval: Duration,
+    cache: Arc<RwLock<CachedKeys>>,
+}
+
+fn verifier_from_jwk(jwk: &Jwk) -> Result<Box<dyn JwsVerifier>, Error> {
+    match jwk.key_type() {
+        "RSA" => Ok(Box::new(RS256.verifier_from_jwk(jwk)?)),
+        "EC" => Ok(Box::new(ES256.verifier_from_jwk(jwk)?)),
+        _ => Err(Error::BadRequest),
+    }
+}
+
+impl Jwks {
+    pub fn new(url: String, refresh_interval: Duration) -> Self {
+        Jwks {
+            url,
+            refresh_interval,
+            cache: Arc::new(RwLock::new(CachedKeys::default())),
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn refresh(&self) -> Result<(), Error> {
+        let body = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?
+            .get(&self.url)
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        let keys = JwkSet::from_bytes(body)?
+            .keys()
+            .into_iter()
+            .filter_map(|jwk| Some((jwk.key_id()?.to_string(), jwk.clone())))
+            .collect();
+
+        let mut cache = self.cache.write().unwrap();
+        cache.fetched = Some(Instant::now());
+        cache.keys = keys;
+        Ok(())
+    }
+
+    // Verifier for a key id from the cached keys
+    pub fn verifier(&self, kid: &str) -> Option<Box<dyn JwsVerifier>> {
+        let cache = self.cache.read().unwrap();
+        let jwk = cache.keys.get(kid)?;
+        verifier_from_jwk(jwk)
+            .map_err(|e| log::warn!("Unusable key {} at {}: {}", kid, self.url, e))
+            .ok()
+    }
+
+    // Verifier for a key id, fetching the keys first when stale or when the key is unknown,
+    // so rotated keys are picked up without waiting for the refresh interval
+    pub async fn fresh_verifier(&self, kid: &str) -> Result<Box<dyn JwsVerifier>, Error> {
+        let needs_refresh = {
+            let cache = self.cache.read().unwrap();
+            match cache.fetched {
+                None => true,
+                Some(fetched) if fetched.elapsed() > self.refresh_interval => true,
+                Some(fetched) => {
+                    !cache.keys.contains_key(kid) && fetched.elapsed() > MIN_REFRESH_INTERVAL
+                }
+            }
+        };
+        if needs_refresh {
+            self.refresh().await?;
+        }
+        self.verifier(kid).ok_or(Error::BadRequest)
+    }
+
+    // Keep the cached keys up to date in the background
+    fn keep_fresh(self) {
+        rocket::tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.refresh().await {
+                    log::error!("Could not fetch keys from {}: {}", self.url, e);
+                }
+                rocket::tokio::time::sleep(self.refresh_interval).await;
+            }
+        });
+    }
+}
+
+pub struct JwksRefreshFairing;
+
+#[rocket::async_trait]
+impl Fairing for JwksRefreshFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Requestor JWKS refresh",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(config) = rocket.state::<CoreConfig>() {
+            for config in config.all_tenants() {
+                for jwks in config.requestor_jwks() {
+                    jwks.clone().keep_fresh();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::Jwks;
+
+    #[test]
+    fn test_jwks() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/.well-known/jwks.json")
+                .method(httpmock::Method::GET);
+            then.status(200).json_body(json!({"keys": [{
+                "kty": "RSA",
+                "kid": "test",
+                "use": "sig",
+                "n": "5_wRrT2T4GGvuQYcWjLr_lFe51sTV2FLd3GAaMiHN8Q_VT_XEhP_kZ6042l1Bj2VpZ2yMxv294JKwBCINc348VLYd-DfkMnJ4yX9LZHK2Wke6tCWBB9mYgGjMwCNdXczbl96x1_HevaTorvk91rzCvzw6vV08jtprAyN5aYMU4I0_cVJwi03bh_skraAB110mQSqi1QU_2z6Hkuf7-_x_bACxviWCyPCd_wkXNpFhTcRlfFeyKcy0pwFx1OLCDJ1qY7oU-z1wcypeOHeiUSxriSHlWaT24ke-J78GGVmnCZdu_MRuun5hvgaiWxnhIBmExJY6vRuMlwkbRqOft5QTQ",
+                "e": "AQAB",
+            }]}));
+        });
+
+        let jwks = Jwks::new(
+            server.url("/.well-known/jwks.json"),
+            Duration::from_secs(300),
+        );
+        assert!(jwks.verifier("test").is_none());
+        assert!(tokio_test::block_on(jwks.fresh_verifier("test")).is_ok());
+        assert!(jwks.clone().verifier("test").is_some());
+
+        // Unknown keys don't trigger a refresh right after fetching
+        assert!(tokio_test::block_on(jwks.fresh_verifier("other")).is_err());
+        mock.assert_hits(1);
+    }
+}
@@ -0,0 +1,302 @@
+// The openapi document exceeds the default limit of the json! macro
+#![recursion_limit = "256"]
+
+mod admin;
+mod alerts;
+mod apikey;
+mod audit;
+mod backpressure;
+mod builder;
+mod catchers;
+pub mod cli;
+mod clientip;
+mod config;
+mod corekeys;
+mod deadletter;
+mod discovery;
+mod endpoints;
+mod error;
+mod errorpage;
+mod events;
+mod health;
+mod idgen;
+mod internal;
+mod jwks;
+mod logging;
+mod methods;
+mod metrics;
+mod mock;
+mod oauth;
+mod openapi;
+mod options;
+mod registry;
+mod requestors;
+mod retention;
+mod select;
+mod sentry;
+mod session;
+mod shorturl;
+mod signer;
+mod start;
+mod teltoken;
+mod tenant;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod tls;
+mod unixsocket;
+mod vault;
+
+#[macro_use]
+extern crate rocket;
+
+pub use builder::CoreConfigBuilder;
+use catchers::{default_catcher, internal_error, not_found, unprocessable_entity};
+pub use config::CoreConfig;
+use config::{effective_config, introspect_token, preview_config};
+use corekeys::core_jwks;
+use deadletter::{list_dead_letters, purge_dead_letter, purge_dead_letters, redeliver_dead_letter};
+use endpoints::set_canary_split;
+pub use error::{ConfigError, ConfigErrors};
+use health::health_report;
+pub use idgen::{install_id_generator, IdGenerator, IdKind};
+use logging::LogConfig;
+use methods::{auth_attr_shim, auth_attr_shim_form, auth_attr_shim_jwt};
+use metrics::{plugin_metrics, plugin_sla};
+use mock::{
+    mock_communication, mock_receive_auth_result, mock_start_authentication,
+    mock_start_communication,
+};
+use openapi::{openapi_document, swagger_ui};
+use options::{all_session_options, attributes, session_options};
+use registry::register_plugin;
+use requestors::{
+    add_requestor, list_requestors, restrict_requestor, revoke_requestor, rotate_requestor_key,
+};
+use retention::retention_report;
+use rocket::{
+    fairing::AdHoc,
+    figment::{self, error::Kind, providers::Serialized, Figment},
+    Build, Rocket, Route,
+};
+use select::{select_comm_page, select_page};
+use session::{
+    consent_records, erase_sessions, requestor_usage, session_auth_result, session_events,
+};
+use shorturl::short_url;
+use start::{
+    session_auth_failed, session_continue, session_next_auth, session_retry_auth, session_return,
+    session_select_comm, session_select_comm_form, session_start, session_start_form,
+    session_start_jwt, session_start_unsupported, session_start_v2, session_start_v2_jwt,
+    session_start_v2_unsupported,
+};
+use teltoken::redeem_tel_token;
+use vault::VaultConfig;
+
+// Set up logging, secrets and background tasks of a core with its routes mounted,
+// checking its configuration. Configuration problems are logged and returned, so the binary
+// decides how to stop.
+pub async fn setup_core(mut base: Rocket<Build>) -> Result<Rocket<Build>, ConfigErrors> {
+    // The logger is needed before the full configuration is parsed, so parse errors get logged
+    match LogConfig::from_figment(base.figment()) {
+        Ok(log_config) => crate::sentry::SentryLogger::init(&log_config),
+        Err(errors) => {
+            crate::sentry::SentryLogger::init(&LogConfig::default());
+            let lines = config_error_lines(*errors);
+            for line in &lines {
+                log::error!("{}", line);
+            }
+            log::error!("{}", ConfigError::Logging);
+            return Err(ConfigErrors(
+                std::iter::once(ConfigError::Logging)
+                    .chain(lines.into_iter().map(ConfigError::Figment))
+                    .collect(),
+            ));
+        }
+    }
+
+    let figment = match with_vault_secrets(base.figment().clone()).await {
+        Ok(figment) => figment,
+        Err(e) => {
+            log::error!("{}: {}", ConfigError::Vault, e);
+            return Err(ConfigErrors(vec![ConfigError::Vault]));
+        }
+    };
+    base = base.configure(figment);
+
+    let config = match base.figment().extract::<CoreConfig>() {
+        Ok(config) => config,
+        Err(errors) => {
+            let lines = config_error_lines(errors);
+            for line in &lines {
+                log::error!("{}", line);
+            }
+            log::error!("Failure to parse configuration");
+            return Err(ConfigErrors(
+                lines.into_iter().map(ConfigError::Figment).collect(),
+            ));
+        }
+    };
+    let base = base
+        .attach(vault::VaultRefreshFairing)
+        .attach(discovery::DiscoveryFairing)
+        .attach(endpoints::EndpointHealthFairing)
+        .attach(jwks::JwksRefreshFairing);
+    Ok(match config.sentry_dsn() {
+        Some(dsn) => base.attach(crate::sentry::SentryFairing::new(dsn, "core", &config)),
+        None => base,
+    })
+}
+
+// Key material kept in vault takes precedence over the configuration files
+async fn with_vault_secrets(figment: Figment) -> Result<Figment, error::Error> {
+    match figment.extract_inner::<VaultConfig>("vault") {
+        Ok(vault) => Ok(figment.merge(Serialized::globals(vault.secrets().await?))),
+        Err(_) => Ok(figment),
+    }
+}
+
+pub(crate) fn config_error_lines(errors: figment::Error) -> Vec<String> {
+    let mut lines = vec![];
+    for error in errors {
+        match error.kind {
+            // Problems found by core itself, which never include configured values
+            Kind::Message(message) => lines.extend(message.lines().map(String::from)),
+            Kind::MissingField(field) => {
+                lines.push(format!("Missing configuration field {}", field))
+            }
+            // Ignore other error values, as they could contain private keys
+            _ => lines.push(format!(
+                "Invalid configuration value at {}",
+                error.path.join(".")
+            )),
+        }
+    }
+    lines
+}
+
+fn public_routes() -> Vec<Route> {
+    routes![
+        all_session_options,
+        session_options,
+        attributes,
+        session_start,
+        session_start_jwt,
+        session_start_form,
+        session_start_unsupported,
+        session_start_v2,
+        session_start_v2_jwt,
+        session_start_v2_unsupported,
+        auth_attr_shim,
+        auth_attr_shim_jwt,
+        auth_attr_shim_form,
+        openapi_document,
+        swagger_ui,
+        core_jwks,
+        select_page,
+        select_comm_page,
+        session_events,
+        session_select_comm,
+        session_select_comm_form,
+        session_next_auth,
+        session_return,
+        session_continue,
+        session_auth_failed,
+        session_retry_auth,
+        short_url,
+        mock_communication,
+    ]
+}
+
+// Routes for operators and plugins, served on the internal listener only when enabled
+fn internal_routes() -> Vec<Route> {
+    routes![
+        session_auth_result,
+        redeem_tel_token,
+        register_plugin,
+        requestor_usage,
+        consent_records,
+        erase_sessions,
+        retention_report,
+        effective_config,
+        preview_config,
+        introspect_token,
+        health_report,
+        list_dead_letters,
+        redeliver_dead_letter,
+        purge_dead_letter,
+        purge_dead_letters,
+        list_requestors,
+        add_requestor,
+        rotate_requestor_key,
+        restrict_requestor,
+        revoke_requestor,
+        set_canary_split,
+        plugin_metrics,
+        plugin_sla,
+        mock_start_authentication,
+        mock_start_communication,
+        mock_receive_auth_result,
+    ]
+}
+
+// Names of all routes, by which operators can leave them out with disabled_routes
+pub(crate) fn route_names() -> Vec<String> {
+    public_routes()
+        .into_iter()
+        .chain(internal_routes())
+        .filter_map(|route| route.name.map(|name| name.to_string()))
+        .collect()
+}
+
+pub fn setup_routes(base: Rocket<Build>) -> Rocket<Build> {
+    // Entry points a deployment doesn't use aren't mounted at all
+    let mut disabled: Vec<String> = base
+        .figment()
+        .extract_inner("disabled_routes")
+        .unwrap_or_default();
+    // Form starts are only posted by the selection page, of core or any of its tenants
+    let tenants: std::collections::HashMap<String, serde_json::Value> =
+        base.figment().extract_inner("tenants").unwrap_or_default();
+    let selection_ui = base
+        .figment()
+        .extract_inner::<bool>("selection_ui")
+        .unwrap_or(false)
+        || tenants
+            .values()
+            .any(|tenant| tenant["selection_ui"] == serde_json::Value::Bool(true));
+    if !selection_ui {
+        disabled.push("session_start_form".to_string());
+    }
+    let enabled = |routes: Vec<Route>| -> Vec<Route> {
+        routes
+            .into_iter()
+            .filter(
+                |route| !matches!(&route.name, Some(name) if disabled.iter().any(|d| d == name)),
+            )
+            .collect()
+    };
+
+    base.mount("/", logging::with_request_ids(enabled(public_routes())))
+        .mount(
+            "/",
+            logging::with_request_ids(internal::internal_only(enabled(internal_routes()))),
+        )
+        .manage(internal::InternalPeers::default())
+        .register(
+            "/",
+            catchers![
+                not_found,
+                unprocessable_entity,
+                internal_error,
+                default_catcher
+            ],
+        )
+        .attach(AdHoc::config::<CoreConfig>())
+        .attach(tenant::TenantFairing)
+        .attach(session::CleanupFairing)
+        .attach(events::EventPublisherFairing)
+        .attach(alerts::AlertFairing)
+        .attach(internal::InternalListenerFairing)
+        .attach(unixsocket::UnixSocketFairing)
+        .attach(logging::RequestLogFairing)
+}
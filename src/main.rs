@@ -1,44 +1,27 @@
-mod config;
-mod error;
-mod methods;
-mod options;
-mod start;
+use id_contact_core::{cli, setup_core, setup_routes};
 
-#[macro_use]
-extern crate rocket;
+#[rocket::launch]
+async fn boot() -> _ {
+    // Provisioning subcommands exit without starting the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Some(code) = cli::run(&args) {
+        std::process::exit(code);
+    }
 
-use config::CoreConfig;
-use methods::auth_attr_shim;
-use options::{all_session_options, session_options};
-use rocket::{fairing::AdHoc, Build};
-use start::{session_start, session_start_jwt};
+    let mut base = setup_routes(rocket::build());
 
-#[launch]
-fn boot() -> _ {
-    id_contact_sentry::SentryLogger::init();
+    // Demo and frontend development setups can run without any real plugins
+    if std::env::args().any(|arg| arg == "--mock-plugins") {
+        let figment = base.figment().clone().merge(("mock_plugins", true));
+        base = base.configure(figment);
+    }
 
-    let base = setup_routes(rocket::build());
-    let config = base.figment().extract::<CoreConfig>().unwrap_or_else(|_| {
-        // Ignore error value, as it could contain private keys
-        log::error!("Failure to parse configuration");
-        panic!("Failure to parse configuration")
-    });
-    match config.sentry_dsn() {
-        Some(dsn) => base.attach(id_contact_sentry::SentryFairing::new(dsn, "core")),
-        None => base,
+    if let Some(code) = cli::run_self_test(&args, base.figment().clone()).await {
+        std::process::exit(code);
     }
-}
 
-fn setup_routes(base: rocket::Rocket<Build>) -> rocket::Rocket<Build> {
-    base.mount(
-        "/",
-        routes![
-            all_session_options,
-            session_options,
-            session_start,
-            session_start_jwt,
-            auth_attr_shim,
-        ],
-    )
-    .attach(AdHoc::config::<CoreConfig>())
+    // Problems were logged while setting up, and are not repeated to keep secrets out of output
+    setup_core(base)
+        .await
+        .unwrap_or_else(|_| std::process::exit(1))
 }
@@ -1,13 +1,376 @@
 mod auth;
 mod comm;
 
-pub use auth::{auth_attr_shim, AuthenticationMethod};
-pub use comm::CommunicationMethod;
+use std::{
+    borrow::Borrow,
+    collections::HashMap,
+    convert::TryFrom,
+    fmt::{Debug, Display},
+    ops::Deref,
+    time::Duration,
+};
 
-pub type Tag = String;
+use crate::{
+    config::REDACTED,
+    tls::{self, PluginTlsConfig},
+};
+
+pub(crate) use auth::shim_continuation;
+pub use auth::{
+    auth_attr_shim, auth_attr_shim_form, auth_attr_shim_jwt, AuthenticationMethod, Loa,
+};
+pub use comm::{CommunicationMethod, RequestContext, RequestedAttribute};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use rocket::{
+    form::{self, FromFormField, ValueField},
+    request::FromParam,
+};
+use serde::{Deserialize, Serialize, Serializer};
+
+const MAX_TAG_LENGTH: usize = 64;
+
+// Tag of a method or purpose. Tags end up in urls, logs and JWT claims, so are limited to
+// letters, digits, '-', '_' and '.'.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Tag(String);
+
+impl TryFrom<String> for Tag {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.is_empty()
+            || value.len() > MAX_TAG_LENGTH
+            || !value
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+        {
+            return Err(format!("Invalid tag {:?}", value));
+        }
+        Ok(Tag(value))
+    }
+}
+
+impl TryFrom<&str> for Tag {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Tag::try_from(value.to_string())
+    }
+}
+
+impl Tag {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<Tag> for String {
+    fn from(tag: Tag) -> Self {
+        tag.0
+    }
+}
+
+impl Deref for Tag {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Borrow<str> for Tag {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<str> for Tag {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for Tag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl PartialEq<str> for Tag {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Tag {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Tag {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+impl PartialEq<Tag> for String {
+    fn eq(&self, other: &Tag) -> bool {
+        self == &other.0
+    }
+}
+
+impl<'v> FromFormField<'v> for Tag {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        Ok(Tag::try_from(field.value).map_err(form::Error::validation)?)
+    }
+}
+
+impl<'a> FromParam<'a> for Tag {
+    type Error = String;
+
+    fn from_param(param: &'a str) -> Result<Self, Self::Error> {
+        Tag::try_from(param)
+    }
+}
 
 pub trait Method {
     fn tag(&self) -> &Tag;
     fn name(&self) -> &str;
     fn image_path(&self) -> &str;
+    fn asset_base_url(&self) -> Option<&str>;
+}
+
+// Newest version of the plugin protocol spoken by core
+const LATEST_PROTOCOL_VERSION: u32 = 2;
+
+// Version of the plugin protocol implemented by a plugin. Version 1 is the base protocol, with
+// core extensions enabled per method through the supports_* flags. Plugins implementing
+// version 2 get all extensions, and the version itself in their start requests.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "u32", into = "u32")]
+pub struct ProtocolVersion(u32);
+
+impl Default for ProtocolVersion {
+    fn default() -> Self {
+        ProtocolVersion(1)
+    }
+}
+
+impl TryFrom<u32> for ProtocolVersion {
+    type Error = String;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        if (1..=LATEST_PROTOCOL_VERSION).contains(&value) {
+            Ok(ProtocolVersion(value))
+        } else {
+            Err(format!("Unsupported plugin protocol version {}", value))
+        }
+    }
+}
+
+impl From<ProtocolVersion> for u32 {
+    fn from(version: ProtocolVersion) -> Self {
+        version.0
+    }
+}
+
+impl ProtocolVersion {
+    // Whether the plugin supports all core extensions, regardless of its supports_* flags
+    pub fn extended(self) -> bool {
+        self.0 >= 2
+    }
+
+    // Version to include in start requests, which plugins of the base protocol don't expect
+    pub fn announced(self) -> Option<u32> {
+        if self.extended() {
+            Some(self.0)
+        } else {
+            None
+        }
+    }
+}
+
+// Tuning of the http clients used for outbound requests to plugins
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PluginClientConfig {
+    // Speak HTTP/2 without negotiating it first, for plugins behind h2c
+    #[serde(default)]
+    http2_prior_knowledge: bool,
+    // Seconds an idle connection is kept in the pool
+    pool_idle_timeout: Option<u64>,
+    pool_max_idle_per_host: Option<usize>,
+    // Seconds between TCP keepalive probes
+    tcp_keepalive: Option<u64>,
+    #[serde(default)]
+    tls: PluginTlsConfig,
+    #[serde(skip)]
+    client: Option<reqwest::Client>,
+}
+
+impl PluginClientConfig {
+    fn builder(&self) -> reqwest::ClientBuilder {
+        let mut builder = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .tcp_keepalive(self.tcp_keepalive.map(Duration::from_secs));
+        if self.http2_prior_knowledge {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(timeout) = self.pool_idle_timeout {
+            builder = builder.pool_idle_timeout(Duration::from_secs(timeout));
+        }
+        if let Some(max) = self.pool_max_idle_per_host {
+            builder = builder.pool_max_idle_per_host(max);
+        }
+        builder
+    }
+
+    // Build the client for plugin urls not belonging to a configured method once, with the
+    // global trust settings only
+    pub fn setup_client(&mut self) -> Result<(), String> {
+        let builder = tls::configure(self.builder(), &self.tls, &PluginTlsConfig::default())?;
+        self.client = Some(builder.build().map_err(|e| e.to_string())?);
+        Ok(())
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client, reqwest::Error> {
+        match &self.client {
+            Some(client) => Ok(client.clone()),
+            None => self.builder().build(),
+        }
+    }
+}
+
+// Static headers added to every outbound request to a plugin, along with the client sending them
+#[derive(Clone, Default, Deserialize)]
+#[serde(try_from = "HashMap<String, String>")]
+pub struct PluginHeaders {
+    headers: HeaderMap,
+    client: Option<reqwest::Client>,
+}
+
+impl TryFrom<HashMap<String, String>> for PluginHeaders {
+    type Error = String;
+
+    fn try_from(value: HashMap<String, String>) -> Result<Self, Self::Error> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in value {
+            let name = HeaderName::try_from(name.as_str())
+                .map_err(|_| format!("Invalid plugin header name {}", name))?;
+            let mut value = HeaderValue::try_from(value.as_str())
+                .map_err(|_| format!("Invalid value for plugin header {}", name))?;
+            // Header values typically contain api keys, so keep them out of logs
+            value.set_sensitive(true);
+            headers.insert(name, value);
+        }
+        Ok(PluginHeaders {
+            headers,
+            client: None,
+        })
+    }
+}
+
+impl Debug for PluginHeaders {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.headers.keys()).finish()
+    }
+}
+
+// Header names only, as the values typically contain api keys
+impl Serialize for PluginHeaders {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.headers.keys().map(|name| (name.as_str(), REDACTED)))
+    }
+}
+
+impl PluginHeaders {
+    // Build the client once, so connections to the plugin are pooled across requests
+    pub fn setup_client(
+        &mut self,
+        config: &PluginClientConfig,
+        tls: &PluginTlsConfig,
+    ) -> Result<(), String> {
+        let builder = config.builder().default_headers(self.headers.clone());
+        let builder = tls::configure(builder, &config.tls, tls)?;
+        self.client = Some(builder.build().map_err(|e| e.to_string())?);
+        Ok(())
+    }
+
+    // Whether the plugin can be reached, any http response counts
+    pub async fn ping(&self, url: &str) -> Result<(), reqwest::Error> {
+        self.client()?.get(url).send().await?;
+        Ok(())
+    }
+
+    pub fn client(&self) -> Result<reqwest::Client, reqwest::Error> {
+        match &self.client {
+            Some(client) => Ok(client.clone()),
+            None => PluginClientConfig::default()
+                .builder()
+                .default_headers(self.headers.clone())
+                .build(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+
+    use httpmock::MockServer;
+    use serde_json::json;
+
+    use super::{PluginClientConfig, PluginHeaders, Tag};
+
+    #[test]
+    fn test_tag() {
+        assert_eq!(Tag::try_from("report_move-2.0").unwrap(), "report_move-2.0");
+        assert!(Tag::try_from("").is_err());
+        assert!(Tag::try_from("irma app").is_err());
+        assert!(Tag::try_from("irma/../admin").is_err());
+        assert!(Tag::try_from("*").is_err());
+        assert!(Tag::try_from("ïrma").is_err());
+        assert!(Tag::try_from("a".repeat(65)).is_err());
+
+        assert_eq!(
+            serde_json::from_value::<Tag>(json!("irma")).unwrap(),
+            Tag::try_from("irma").unwrap()
+        );
+        assert!(serde_json::from_value::<Tag>(json!("irma\n")).is_err());
+        assert_eq!(
+            serde_json::to_value(Tag::try_from("irma").unwrap()).unwrap(),
+            json!("irma")
+        );
+    }
+
+    #[test]
+    fn test_shared_client() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.path("/").header("Authorization", "Bearer test");
+            then.status(200);
+        });
+
+        let config: PluginClientConfig = serde_json::from_value(json!({
+            "pool_idle_timeout": 30,
+            "pool_max_idle_per_host": 1,
+            "tcp_keepalive": 60,
+        }))
+        .unwrap();
+        let mut headers = PluginHeaders::try_from(
+            vec![("Authorization".to_string(), "Bearer test".to_string())]
+                .into_iter()
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+        .unwrap();
+        headers.setup_client(&config, &Default::default()).unwrap();
+
+        for _ in 0..2 {
+            let response =
+                tokio_test::block_on(headers.client().unwrap().get(server.base_url()).send());
+            assert!(response.unwrap().status().is_success());
+        }
+        mock.assert_hits(2);
+    }
 }
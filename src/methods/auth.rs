@@ -1,91 +1,333 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::HashMap, convert::TryFrom, time::Duration};
 
-use crate::config::CoreConfig;
+use crate::config::{CoreConfig, URLSTATE_TTL};
+use crate::discovery::Discovery;
+use crate::endpoints::{Canary, Endpoints};
+use crate::tls::PluginTlsConfig;
+use id_contact_jwt::SignKeyConfig;
 use josekit::{
-    jws::JwsHeader,
-    jwt::{self, JwtPayload},
+    jws::{JwsHeader, JwsVerifier},
+    jwt::{self, JwtPayload, JwtPayloadValidator},
 };
 
-use super::{Method, Tag};
-use crate::error::Error;
+use super::{Method, PluginClientConfig, PluginHeaders, ProtocolVersion, Tag};
+use crate::{
+    audit::AuditEvent,
+    deadletter::FailedDelivery,
+    error::{Error, ErrorRedirect},
+    metrics::PluginCall,
+    sentry::send_traced,
+    session::SessionEvent,
+};
 use id_contact_proto::{StartAuthRequest, StartAuthResponse};
-use rocket::{response::Redirect, State};
-use serde::Deserialize;
+use rocket::{form::Form, response::Redirect, Shutdown};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Validity of signed tel continuations
+const CONTINUATION_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Level of assurance of an authentication method, ordered as in eIDAS
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Loa {
+    Low,
+    Substantial,
+    High,
+}
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct AuthenticationMethod {
     tag: Tag,
     name: String,
     image_path: String,
-    start: String,
+    // Overrides the global asset_base_url for the image of this method
+    asset_base_url: Option<String>,
+    #[serde(alias = "start_primary")]
+    start: Endpoints,
+    // Endpoints of a new version of the plugin, see set_canary_split
+    start_canary: Option<Canary>,
+    // Find plugin instances through service discovery, using start until any are found
+    discovery: Option<Discovery>,
+    #[serde(default)]
+    protocol_version: ProtocolVersion,
     #[serde(default = "bool::default")]
     disable_attr_url: bool,
     #[serde(default = "bool::default")]
     shim_tel_url: bool,
+    // Further uri schemes of continuations to pass through a core UI, e.g. sip
+    #[serde(default)]
+    shim_schemes: Vec<String>,
+    #[serde(default)]
+    headers: PluginHeaders,
+    // Trust settings for this plugin on top of the global ones
+    #[serde(default)]
+    tls: PluginTlsConfig,
+    #[serde(default)]
+    attribute_mapping: HashMap<String, String>,
+    #[serde(default = "bool::default")]
+    supports_attribute_alternatives: bool,
+    // Send the core session id along, so logs of the plugin can be correlated with core's
+    #[serde(default = "bool::default")]
+    supports_session_id: bool,
+    // Send a url to redirect the user to when authentication is cancelled or fails
+    #[serde(default = "bool::default")]
+    supports_failure_url: bool,
+    // Key with which the plugin signs its results, checked before the attribute url shim
+    // forwards them. Only usable with plugins returning signed, unencrypted results.
+    #[serde(default, skip_serializing)]
+    result_key: Option<SignKeyConfig>,
+    // Overrides of the global tel shim settings for this method
+    tel_shim_ttl: Option<u64>,
+    #[serde(default)]
+    tel_shim_claims: HashMap<String, Value>,
+    // Level of assurance of the identification, unknown when absent
+    loa: Option<Loa>,
+}
+
+// Start request including core-specific extensions of the plugin protocol
+#[derive(Debug, Serialize)]
+struct ExtendedStartAuthRequest {
+    #[serde(flatten)]
+    request: StartAuthRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attribute_alternatives: Option<Vec<Vec<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol_version: Option<u32>,
 }
 
 impl AuthenticationMethod {
+    pub fn loa(&self) -> Option<Loa> {
+        self.loa
+    }
+
     pub async fn start(
         &self,
         attributes: &[String],
+        attribute_alternatives: &[Vec<String>],
         continuation: &str,
         attr_url: &Option<String>,
+        session_id: &str,
         config: &CoreConfig,
     ) -> Result<String, Error> {
-        let continuation = self.parse_continuation(continuation, config);
+        let continuation = self.parse_continuation(continuation, session_id, config)?;
         if let Some(attr_url) = attr_url {
             if self.disable_attr_url {
                 return self
-                    .start_fallback(attributes, continuation, attr_url, config)
+                    .start_fallback(
+                        attributes,
+                        attribute_alternatives,
+                        continuation,
+                        attr_url,
+                        session_id,
+                        config,
+                    )
                     .await;
             }
         }
 
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()?;
-
-        Ok(client
-            .post(&format!("{}/start_authentication", self.start))
-            .json(&StartAuthRequest {
-                attributes: attributes.to_vec(),
+        self.send_start(
+            self.start_request(
+                attributes,
+                attribute_alternatives,
                 continuation,
-                attr_url: attr_url.clone(),
-            })
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<StartAuthResponse>()
-            .await?
-            .client_url)
+                attr_url.clone(),
+                session_id,
+                config,
+            )?,
+            session_id,
+            config,
+        )
+        .await
+    }
+
+    pub fn setup_client(&mut self, config: &PluginClientConfig) -> Result<(), String> {
+        self.headers.setup_client(config, &self.tls)
+    }
+
+    // Point the method at the mock plugins served by core
+    pub fn use_mock(&mut self, mock_url: &str) {
+        self.start = mock_url.into();
+        self.start_canary = None;
+        self.discovery = None;
+    }
+
+    pub fn discovery(&self) -> Option<&Discovery> {
+        self.discovery.as_ref()
+    }
+
+    pub async fn ping(&self) -> Result<(), reqwest::Error> {
+        self.headers.ping(&self.start_url().0).await
+    }
+
+    pub fn canary(&self) -> Option<&Canary> {
+        self.start_canary.as_ref()
+    }
+
+    // Url to start a session at, and whether it is that of the canary
+    fn start_url(&self) -> (String, bool) {
+        match (&self.start_canary, &self.discovery) {
+            (Some(canary), _) if canary.picked() => (canary.endpoints().url().to_string(), true),
+            (_, Some(discovery)) => (discovery.url(self.start.url()), false),
+            _ => (self.start.url().to_string(), false),
+        }
+    }
+
+    pub fn watch_endpoints(&self, interval: Duration, shutdown: &Shutdown) {
+        let canary = self.start_canary.as_ref().map(Canary::endpoints);
+        for endpoints in std::iter::once(&self.start).chain(canary) {
+            if endpoints.is_weighted() {
+                endpoints.clone().watch(
+                    self.headers.clone(),
+                    self.tag.clone(),
+                    interval,
+                    shutdown.clone(),
+                );
+            }
+        }
+    }
+
+    // Validity of the continuation of a session, as far as it is limited by core
+    pub fn continuation_ttl(
+        &self,
+        continuation: &str,
+        attr_url: &Option<String>,
+        config: &CoreConfig,
+    ) -> Option<Duration> {
+        if attr_url.is_some() && self.disable_attr_url {
+            Some(URLSTATE_TTL)
+        } else if self.shim_url(continuation, config).is_some() {
+            Some(self.tel_shim_ttl(config))
+        } else {
+            None
+        }
+    }
+
+    fn tel_shim_ttl(&self, config: &CoreConfig) -> Duration {
+        self.tel_shim_ttl
+            .map(Duration::from_secs)
+            .or_else(|| config.tel_shim_ttl())
+            .unwrap_or(CONTINUATION_TTL)
     }
 
     // Start session using fallback shim for attribute url handling
     async fn start_fallback(
         &self,
         attributes: &[String],
+        attribute_alternatives: &[Vec<String>],
         continuation: String,
         attr_url: &str,
+        session_id: &str,
         config: &CoreConfig,
     ) -> Result<String, Error> {
         // Prepare session state for url
         let mut state = HashMap::new();
-        state.insert("attr_url".to_string(), attr_url.to_string());
-        state.insert("continuation".to_string(), continuation.to_string());
+        state.insert("attr_url".to_string(), Value::from(attr_url));
+        state.insert("continuation".to_string(), Value::from(continuation));
+        state.insert("session_id".to_string(), Value::from(session_id));
+        state.insert("auth_method".to_string(), Value::from(self.tag.as_str()));
         let state = config.encode_urlstate(state)?;
 
         // Start auth session
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()?;
-        Ok(client
-            .post(&format!("{}/start_authentication", self.start))
-            .json(&StartAuthRequest {
-                attributes: attributes.to_vec(),
-                continuation: format!("{}/auth_attr_shim/{}", config.server_url(), state),
-                attr_url: None,
-            })
-            .send()
+        self.send_start(
+            self.start_request(
+                attributes,
+                attribute_alternatives,
+                format!("{}/auth_attr_shim/{}", config.server_url(), state),
+                None,
+                session_id,
+                config,
+            )?,
+            session_id,
+            config,
+        )
+        .await
+    }
+
+    fn start_request(
+        &self,
+        attributes: &[String],
+        attribute_alternatives: &[Vec<String>],
+        continuation: String,
+        attr_url: Option<String>,
+        session_id: &str,
+        config: &CoreConfig,
+    ) -> Result<ExtendedStartAuthRequest, Error> {
+        let extended = self.protocol_version.extended();
+
+        // Plugins not supporting alternatives only get the primary attribute set
+        let attribute_alternatives = if (self.supports_attribute_alternatives || extended)
+            && !attribute_alternatives.is_empty()
+        {
+            Some(
+                attribute_alternatives
+                    .iter()
+                    .map(|set| self.map_attributes(set))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+
+        Ok(ExtendedStartAuthRequest {
+            request: StartAuthRequest {
+                attributes: self.map_attributes(attributes),
+                continuation,
+                attr_url,
+            },
+            attribute_alternatives,
+            session_id: if self.supports_session_id || extended {
+                Some(session_id.to_string())
+            } else {
+                None
+            },
+            language: if extended {
+                config.sessions().language(session_id)
+            } else {
+                None
+            },
+            failure_url: if self.supports_failure_url || extended {
+                Some(format!(
+                    "{}/session/{}/auth_failed/{}",
+                    config.server_url(),
+                    session_id,
+                    config.step_token(session_id, "auth_failed")?
+                ))
+            } else {
+                None
+            },
+            protocol_version: self.protocol_version.announced(),
+        })
+    }
+
+    async fn send_start(
+        &self,
+        request: ExtendedStartAuthRequest,
+        session_id: &str,
+        config: &CoreConfig,
+    ) -> Result<String, Error> {
+        let client = self.headers.client()?;
+
+        let (url, canary) = self.start_url();
+        let request = client
+            .post(&format!("{}/start_authentication", url))
+            .json(&request);
+        let purpose = config.sessions().purpose(session_id).unwrap_or_default();
+        let call = PluginCall {
+            tenant: config.tenant_name(),
+            kind: "auth",
+            method: &self.tag,
+            purpose: &purpose,
+            call: "start_authentication",
+            canary,
+        };
+        Ok(send_traced(&client, request, call)
             .await?
             .error_for_status()?
             .json::<StartAuthResponse>()
@@ -93,30 +335,143 @@ impl AuthenticationMethod {
             .client_url)
     }
 
-    fn parse_continuation(&self, continuation: &str, config: &CoreConfig) -> String {
-        if continuation.starts_with("tel:") && self.shim_tel_url {
-            let token = sign_continuation(continuation, config);
-            format!("{}{}", config.ui_tel_url(), &token)
+    // Translate canonical attribute names to the names used by this plugin
+    fn map_attributes(&self, attributes: &[String]) -> Vec<String> {
+        attributes
+            .iter()
+            .map(|attr| self.attribute_mapping.get(attr).unwrap_or(attr).to_string())
+            .collect()
+    }
+
+    // Check that a result passed to the attribute url shim was produced by this plugin
+    fn verify_result(&self, result: &str) -> Result<(), Error> {
+        let key = match &self.result_key {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+        let verifier = Box::<dyn JwsVerifier>::try_from(key.clone())?;
+        let (payload, _) = jwt::decode_with_verifier(result, verifier.as_ref()).map_err(|e| {
+            log::warn!(
+                "Invalid result signature from auth plugin {}: {}",
+                self.tag,
+                e
+            );
+            Error::BadRequest
+        })?;
+        let mut validator = JwtPayloadValidator::new();
+        validator.set_base_time(std::time::SystemTime::now());
+        validator.validate(&payload).map_err(|e| {
+            log::warn!("Invalid result from auth plugin {}: {}", self.tag, e);
+            Error::BadRequest
+        })
+    }
+
+    fn sign_continuation(
+        &self,
+        continuation: &str,
+        session_id: &str,
+        config: &CoreConfig,
+    ) -> Result<String, Error> {
+        // Static claims for the shim UI, with those of the method taking precedence
+        let claims = config.tel_shim_claims().iter().chain(&self.tel_shim_claims);
+        shim_token(
+            continuation,
+            session_id,
+            claims,
+            self.tel_shim_ttl(config),
+            config,
+        )
+    }
+
+    pub fn shim_schemes(&self) -> impl Iterator<Item = &str> {
+        let tel = if self.shim_tel_url { Some("tel") } else { None };
+        tel.into_iter()
+            .chain(self.shim_schemes.iter().map(|scheme| scheme.as_str()))
+    }
+
+    // UI to pass the continuation through, if its scheme is shimmed for this method
+    fn shim_url<'a>(&self, continuation: &str, config: &'a CoreConfig) -> Option<&'a str> {
+        let scheme = &continuation[..continuation.find(':')?];
+        if self.shim_schemes().any(|s| s == scheme) {
+            config.ui_shim_url(scheme)
+        } else {
+            None
+        }
+    }
+
+    fn parse_continuation(
+        &self,
+        continuation: &str,
+        session_id: &str,
+        config: &CoreConfig,
+    ) -> Result<String, Error> {
+        if let Some(ui_url) = self.shim_url(continuation, config) {
+            let token = self.sign_continuation(continuation, session_id, config)?;
+            let token = match config.tel_tokens() {
+                Some(tel_tokens) => {
+                    tel_tokens.issue(token, session_id, self.tel_shim_ttl(config))?
+                }
+                None => token,
+            };
+            Ok(format!("{}{}", ui_url, &token))
         } else {
-            continuation.to_string()
+            Ok(continuation.to_string())
         }
     }
 }
 
-fn sign_continuation(continuation: &str, config: &CoreConfig) -> String {
+// Token for the UI shimming a continuation, signed by core and bound to the session
+fn shim_token<'a>(
+    continuation: &str,
+    session_id: &str,
+    claims: impl Iterator<Item = (&'a String, &'a Value)>,
+    ttl: Duration,
+    config: &CoreConfig,
+) -> Result<String, Error> {
     let mut payload = JwtPayload::new();
+    for (k, v) in claims {
+        payload.set_claim(k, Some(v.clone()))?;
+    }
+
     payload.set_issued_at(&std::time::SystemTime::now());
 
     // expires_at is set to the expiry time of a DTMF code
-    payload
-        .set_expires_at(&(std::time::SystemTime::now() + std::time::Duration::from_secs(60 * 60)));
-    payload
-        .set_claim(
-            "continuation",
-            Some(serde_json::to_value(continuation).unwrap()),
-        )
-        .unwrap();
-    jwt::encode_with_signer(&payload, &JwsHeader::new(), config.ui_signer()).unwrap()
+    payload.set_expires_at(&(std::time::SystemTime::now() + ttl));
+    payload.set_claim("continuation", Some(Value::from(continuation)))?;
+    payload.set_claim("session_id", Some(Value::from(session_id)))?;
+    Ok(jwt::encode_with_signer(
+        &payload,
+        &JwsHeader::new(),
+        config.ui_signer(),
+    )?)
+}
+
+// Url of the UI shimming a continuation of another scheme than http, if one is configured
+pub(crate) fn shim_continuation(
+    continuation: &str,
+    session_id: &str,
+    config: &CoreConfig,
+) -> Result<Option<String>, Error> {
+    let ui_url = match continuation
+        .find(':')
+        .and_then(|end| config.ui_shim_url(&continuation[..end]))
+    {
+        Some(ui_url) => ui_url,
+        None => return Ok(None),
+    };
+    let ttl = config.tel_shim_ttl().unwrap_or(CONTINUATION_TTL);
+    let token = shim_token(
+        continuation,
+        session_id,
+        config.tel_shim_claims().iter(),
+        ttl,
+        config,
+    )?;
+    let token = match config.tel_tokens() {
+        Some(tel_tokens) => tel_tokens.issue(token, session_id, ttl)?,
+        None => token,
+    };
+    Ok(Some(format!("{}{}", ui_url, token)))
 }
 
 impl Method for AuthenticationMethod {
@@ -131,29 +486,171 @@ impl Method for AuthenticationMethod {
     fn image_path(&self) -> &str {
         &self.image_path
     }
+
+    fn asset_base_url(&self) -> Option<&str> {
+        self.asset_base_url.as_deref()
+    }
 }
 
 #[get("/auth_attr_shim/<state>?<result>")]
 pub async fn auth_attr_shim(
     state: String,
     result: String,
-    config: &State<CoreConfig>,
+    config: &CoreConfig,
+) -> Result<Redirect, ErrorRedirect> {
+    forward_result(state, result, config).await
+}
+
+// Variants for plugins delivering their result in the body of a POST
+#[post(
+    "/auth_attr_shim/<state>",
+    format = "application/jwt",
+    data = "<result>"
+)]
+pub async fn auth_attr_shim_jwt(
+    state: String,
+    result: String,
+    config: &CoreConfig,
+) -> Result<Redirect, ErrorRedirect> {
+    forward_result(state, result, config).await
+}
+
+#[derive(FromForm)]
+pub struct ShimResult {
+    result: String,
+}
+
+#[post(
+    "/auth_attr_shim/<state>",
+    format = "application/x-www-form-urlencoded",
+    data = "<result>"
+)]
+pub async fn auth_attr_shim_form(
+    state: String,
+    result: Form<ShimResult>,
+    config: &CoreConfig,
+) -> Result<Redirect, ErrorRedirect> {
+    forward_result(state, result.into_inner().result, config).await
+}
+
+async fn forward_result(
+    state: String,
+    result: String,
+    config: &CoreConfig,
+) -> Result<Redirect, ErrorRedirect> {
+    let error_url = config
+        .urlstate_session(&state)
+        .and_then(|session_id| config.session_error_url(&session_id));
+    deliver_result(state, result, config)
+        .await
+        .map_err(|e| e.redirect_to(error_url))
+}
+
+async fn deliver_result(
+    state: String,
+    result: String,
+    config: &CoreConfig,
 ) -> Result<Redirect, Error> {
     // Unpack session state
     let state = config.decode_urlstate(state)?;
-    let attr_url = state.get("attr_url").ok_or(Error::BadRequest)?;
-    let continuation = state.get("continuation").ok_or(Error::BadRequest)?;
-
-    // Send through results
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(5))
-        .build()?;
-    client
+    let attr_url = state
+        .get("attr_url")
+        .and_then(Value::as_str)
+        .ok_or(Error::BadRequest)?;
+    let continuation = state
+        .get("continuation")
+        .and_then(Value::as_str)
+        .ok_or(Error::BadRequest)?;
+
+    // Don't let the shim be used to push arbitrary payloads to attribute urls
+    if let Some(method) = state
+        .get("auth_method")
+        .and_then(Value::as_str)
+        .and_then(|tag| config.find_auth_method(tag))
+    {
+        method.verify_result(&result)?;
+    }
+
+    let session_id = state.get("session_id").and_then(Value::as_str);
+    // Only send users on to the continuation signed for their session
+    if let (Some(session_id), true) = (session_id, config.sign_continuations()) {
+        config.check_continuation_url(session_id, continuation)?;
+    }
+    let purpose = session_id
+        .and_then(|id| config.sessions().purpose(id))
+        .unwrap_or_default();
+
+    // Bind the result to its session for comm plugins supporting envelopes
+    let comm_method = session_id.and_then(|id| config.sessions().comm_method(id));
+    let plugin = comm_method
+        .as_deref()
+        .and_then(|tag| config.find_comm_method(tag));
+    let enveloped = match session_id.zip(plugin.as_ref()) {
+        Some((id, plugin)) => plugin.envelope(&result, &purpose, id, config.ui_signer())?,
+        None => result.clone(),
+    };
+
+    // Send through results, trusting the plugin as its own calls do
+    let client = match &plugin {
+        Some(plugin) => plugin.client()?,
+        None => config.plugin_client().client()?,
+    };
+    let request = client
         .post(attr_url)
         .header("Content-Type", "application/jwt")
-        .body(result)
-        .send()
-        .await?;
+        .body(enveloped);
+    let call = PluginCall {
+        tenant: config.tenant_name(),
+        kind: "auth",
+        method: state
+            .get("auth_method")
+            .and_then(Value::as_str)
+            .unwrap_or_default(),
+        purpose: &purpose,
+        call: "deliver_auth_result",
+        canary: false,
+    };
+    let delivery = send_traced(&client, request, call)
+        .await
+        .and_then(|response| response.error_for_status());
+    // Plugins refusing the result or failing on their side didn't take it either
+    let failure = delivery.as_ref().err().map(|e| match e.status() {
+        Some(status) => format!("Status {}", status),
+        None => e.to_string(),
+    });
+    if let (Some(error), Some(dead_letters)) = (failure, config.dead_letters()) {
+        let delivery = FailedDelivery {
+            attr_url,
+            auth_result: &result,
+            purpose: &purpose,
+            auth_method: state.get("auth_method").and_then(Value::as_str),
+            session_id,
+            comm_method: comm_method.as_deref(),
+            error,
+        };
+        dead_letters.add(delivery, config).await;
+    }
+
+    config
+        .audit(AuditEvent::ShimDelivery {
+            purpose: purpose.clone(),
+            session_id: session_id.map(|id| id.to_string()),
+            auth_method: state
+                .get("auth_method")
+                .and_then(Value::as_str)
+                .map(|tag| tag.to_string()),
+            success: delivery.is_ok(),
+        })
+        .await;
+    if let Some(session_id) = session_id {
+        config.sessions().publish(
+            session_id,
+            SessionEvent::AuthResultDelivered {
+                success: delivery.is_ok(),
+            },
+        );
+    }
+    delivery?;
 
     // Redirect user
     Ok(Redirect::to(continuation.to_string()))
@@ -164,15 +661,25 @@ mod tests {
     use figment::providers::{Format, Toml};
     use httpmock::MockServer;
     use id_contact_proto::StartAuthRequest;
-    use rocket::{figment::Figment, local::blocking::Client};
+    use josekit::{
+        jws::JwsHeader,
+        jwt::{self, JwtPayload},
+    };
+    use rocket::{
+        figment::Figment,
+        http::{ContentType, Status},
+        local::blocking::Client,
+    };
     use serde_json::json;
+    use std::{collections::HashMap, convert::TryFrom, time::Duration};
 
-    use crate::{config::CoreConfig, setup_routes};
+    use crate::{config::CoreConfig, methods::Tag, setup_routes};
 
     const TEST_CONFIG_VALID: &'static str = r#"
 [global]
 server_url = "https://core.idcontact.test.tweede.golf"
 internal_url = "http://core:8000"
+public_internal_routes = true
 internal_secret = "sample_secret_1234567890178901237890"
 ui_tel_url = "https://poc.idcontact.test.tweede.golf/tel/"
 
@@ -296,18 +803,35 @@ allowed_comm = [ "call" ]
         });
 
         let method = super::AuthenticationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attr_url: false,
             shim_tel_url: false,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
         };
 
         let result = tokio_test::block_on(method.start(
             &vec!["email".into()],
+            &[],
             "https://example.com/continuation",
             &Some("https://example.com/attr_url".into()),
+            "test",
             &config,
         ));
 
@@ -341,18 +865,35 @@ allowed_comm = [ "call" ]
         });
 
         let method = super::AuthenticationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attr_url: false,
             shim_tel_url: false,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
         };
 
         let result = tokio_test::block_on(method.start(
             &vec!["email".into()],
+            &[],
             "https://example.com/continuation",
             &None,
+            "test",
             &config,
         ));
 
@@ -360,6 +901,197 @@ allowed_comm = [ "call" ]
         assert_eq!(result.unwrap(), "https://example.com/client_url");
     }
 
+    #[test]
+    fn test_start_attribute_mapping() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested());
+
+        let config = figment.extract::<CoreConfig>().unwrap();
+
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_authentication")
+                .method(httpmock::Method::POST)
+                .json_body(json!({
+                    "attributes": [
+                        "pbdf.sidn-pbdf.email.email",
+                        "name",
+                    ],
+                    "continuation": "https://example.com/continuation",
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url",
+                }));
+        });
+
+        let method = super::AuthenticationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attr_url: false,
+            shim_tel_url: false,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: vec![("email".into(), "pbdf.sidn-pbdf.email.email".into())]
+                .into_iter()
+                .collect(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
+        };
+
+        let result = tokio_test::block_on(method.start(
+            &vec!["email".into(), "name".into()],
+            &[],
+            "https://example.com/continuation",
+            &None,
+            "test",
+            &config,
+        ));
+
+        start_mock.assert();
+        assert_eq!(result.unwrap(), "https://example.com/client_url");
+    }
+
+    #[test]
+    fn test_start_attribute_alternatives() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested());
+
+        let config = figment.extract::<CoreConfig>().unwrap();
+
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_authentication")
+                .method(httpmock::Method::POST)
+                .json_body(json!({
+                    "attributes": [
+                        "email",
+                    ],
+                    "attribute_alternatives": [
+                        ["email"],
+                        ["phone"],
+                    ],
+                    "continuation": "https://example.com/continuation",
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url",
+                }));
+        });
+
+        let mut method = super::AuthenticationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attr_url: false,
+            shim_tel_url: false,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: true,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
+        };
+
+        let alternatives = vec![vec!["email".into()], vec!["phone".into()]];
+        let result = tokio_test::block_on(method.start(
+            &vec!["email".into()],
+            &alternatives,
+            "https://example.com/continuation",
+            &None,
+            "test",
+            &config,
+        ));
+
+        start_mock.assert();
+        assert_eq!(result.unwrap(), "https://example.com/client_url");
+
+        // Plugins without support only receive the primary attribute set
+        method.supports_attribute_alternatives = false;
+        let request = method
+            .start_request(
+                &vec!["email".into()],
+                &alternatives,
+                "https://example.com/continuation".into(),
+                None,
+                "session",
+                &config,
+            )
+            .unwrap();
+        assert_eq!(request.attribute_alternatives, None);
+        assert_eq!(request.request.attributes, vec!["email"]);
+        assert_eq!(request.session_id, None);
+
+        method.supports_session_id = true;
+        let request = method
+            .start_request(
+                &vec!["email".into()],
+                &[],
+                "https://example.com/continuation".into(),
+                None,
+                "session",
+                &config,
+            )
+            .unwrap();
+        assert_eq!(request.session_id, Some("session".into()));
+
+        let session_id = config.sessions().create();
+        config
+            .sessions()
+            .set_language(&session_id, Some("nl".into()));
+        let request = method
+            .start_request(
+                &vec!["email".into()],
+                &[],
+                "https://example.com/continuation".into(),
+                None,
+                &session_id,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(request.language, None);
+
+        // Only plugins of the extended protocol get the language
+        method.protocol_version = serde_json::from_value(json!(2)).unwrap();
+        let request = method
+            .start_request(
+                &vec!["email".into()],
+                &[],
+                "https://example.com/continuation".into(),
+                None,
+                &session_id,
+                &config,
+            )
+            .unwrap();
+        assert_eq!(request.language, Some("nl".into()));
+    }
+
     #[test]
     fn test_attr_shim_start() {
         let figment = Figment::from(rocket::Config::default())
@@ -394,18 +1126,35 @@ allowed_comm = [ "call" ]
         });
 
         let method = super::AuthenticationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attr_url: true,
             shim_tel_url: false,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
         };
 
         let result = tokio_test::block_on(method.start(
             &vec!["email".into()],
+            &[],
             "https://example.com/continuation",
             &Some("https://example.com/attr_url".into()),
+            "test",
             &config,
         ));
 
@@ -447,18 +1196,35 @@ allowed_comm = [ "call" ]
         });
 
         let method = super::AuthenticationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attr_url: false,
             shim_tel_url: true,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
         };
 
         let result = tokio_test::block_on(method.start(
             &vec!["email".into()],
+            &[],
             "tel:0123456789",
             &Some("https://example.com/attr_url".into()),
+            "test",
             &config,
         ));
 
@@ -493,18 +1259,35 @@ allowed_comm = [ "call" ]
         });
 
         let method = super::AuthenticationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attr_url: false,
             shim_tel_url: true,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
         };
 
         let result = tokio_test::block_on(method.start(
             &vec!["email".into()],
+            &[],
             "https://example.com/continuation",
             &Some("https://example.com/attr_url".into()),
+            "test",
             &config,
         ));
 
@@ -524,6 +1307,7 @@ allowed_comm = [ "call" ]
 [global]
 server_url = ""
 internal_url = "https://example.com/should_not_be_used"
+public_internal_routes = true
 internal_secret = "sample_secret_1234567890178901237890"
 ui_tel_url = "https://poc.idcontact.test.tweede.golf/tel/"
 
@@ -640,8 +1424,10 @@ allowed_comm = [ "test" ]
         // Do start request
         let result = tokio_test::block_on(config.auth_methods["test"].start(
             &vec!["email".into()],
+            &[],
             "https://example.com/continuation",
             &Some(format!("{}/attr_url", server.base_url())),
+            "test",
             &config,
         ));
 
@@ -661,4 +1447,378 @@ allowed_comm = [ "test" ]
             Some("https://example.com/continuation".into())
         );
     }
+
+    #[test]
+    fn test_verify_result() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested());
+
+        let config = figment.extract::<CoreConfig>().unwrap();
+
+        let result_key = serde_json::from_value(json!({
+            "type": "RSA",
+            "key": "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA5/wRrT2T4GGvuQYcWjLr
+/lFe51sTV2FLd3GAaMiHN8Q/VT/XEhP/kZ6042l1Bj2VpZ2yMxv294JKwBCINc34
+8VLYd+DfkMnJ4yX9LZHK2Wke6tCWBB9mYgGjMwCNdXczbl96x1/HevaTorvk91rz
+Cvzw6vV08jtprAyN5aYMU4I0/cVJwi03bh/skraAB110mQSqi1QU/2z6Hkuf7+/x
+/bACxviWCyPCd/wkXNpFhTcRlfFeyKcy0pwFx1OLCDJ1qY7oU+z1wcypeOHeiUSx
+riSHlWaT24ke+J78GGVmnCZdu/MRuun5hvgaiWxnhIBmExJY6vRuMlwkbRqOft5Q
+TQIDAQAB
+-----END PUBLIC KEY-----
+",
+        }))
+        .unwrap();
+
+        let method = super::AuthenticationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: "http://auth-test:8000".into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attr_url: true,
+            shim_tel_url: false,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: Some(result_key),
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
+        };
+
+        let sign = |expires_at| {
+            let mut payload = JwtPayload::new();
+            payload.set_expires_at(&expires_at);
+            jwt::encode_with_signer(&payload, &JwsHeader::new(), config.ui_signer()).unwrap()
+        };
+        let now = std::time::SystemTime::now();
+        assert!(method
+            .verify_result(&sign(now + Duration::from_secs(60)))
+            .is_ok());
+        assert!(method
+            .verify_result(&sign(now - Duration::from_secs(60)))
+            .is_err());
+        assert!(method.verify_result("test").is_err());
+    }
+
+    #[test]
+    fn test_attr_shim_post() {
+        let server = MockServer::start();
+        let attr_mock = server.mock(|when, then| {
+            when.path("/attr_url")
+                .method(httpmock::Method::POST)
+                .header("Content-Type", "application/jwt")
+                .body("test");
+            then.status(200);
+        });
+
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested());
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+        let config = client.rocket().state::<CoreConfig>().unwrap();
+
+        let mut state = HashMap::new();
+        state.insert("attr_url".to_string(), json!(server.url("/attr_url")));
+        state.insert(
+            "continuation".to_string(),
+            json!("https://example.com/continuation"),
+        );
+        let state = config.encode_urlstate(state).unwrap();
+
+        let response = client
+            .post(format!("/auth_attr_shim/{}", state))
+            .header(ContentType::Form)
+            .body("result=test")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://example.com/continuation")
+        );
+
+        let response = client
+            .post(format!("/auth_attr_shim/{}", state))
+            .header(ContentType::new("application", "jwt"))
+            .body("test")
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        attr_mock.assert_hits(2);
+    }
+
+    #[test]
+    fn test_attr_shim_signed_continuation() {
+        let server = MockServer::start();
+        let attr_mock = server.mock(|when, then| {
+            when.path("/attr_url");
+            then.status(200);
+        });
+
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested())
+            .merge(("sign_continuations", true));
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+        let config = client.rocket().state::<CoreConfig>().unwrap();
+        let session_id = config.sessions().create();
+        config.sessions().set_purpose(&session_id, "report_move");
+        config.sessions().set_comm_method(&session_id, "call");
+
+        let shim = |continuation: String| {
+            let mut state = HashMap::new();
+            state.insert("attr_url".to_string(), json!(server.url("/attr_url")));
+            state.insert("continuation".to_string(), json!(continuation));
+            state.insert("session_id".to_string(), json!(session_id));
+            let state = config.encode_urlstate(state).unwrap();
+            client
+                .post(format!("/auth_attr_shim/{}", state))
+                .header(ContentType::new("application", "jwt"))
+                .body("test")
+                .dispatch()
+        };
+
+        // Continuations swapped for unsigned ones or those of other sessions are refused
+        let response = shim("https://example.com/continuation".into());
+        assert_eq!(response.status(), Status::BadRequest);
+        let other_id = config.sessions().create();
+        config.sessions().set_purpose(&other_id, "report_move");
+        config.sessions().set_comm_method(&other_id, "call");
+        let other = config
+            .sign_continuation(
+                "https://example.com/other",
+                "report_move",
+                "call",
+                &other_id,
+            )
+            .unwrap();
+        let response = shim(other.replace(&other_id, &session_id));
+        assert_eq!(response.status(), Status::BadRequest);
+        attr_mock.assert_hits(0);
+
+        let continuation = config
+            .sign_continuation(
+                "https://example.com/continuation",
+                "report_move",
+                "call",
+                &session_id,
+            )
+            .unwrap();
+        let response = shim(continuation.clone());
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some(continuation.as_str())
+        );
+        attr_mock.assert();
+    }
+
+    #[test]
+    fn test_attr_shim_error_url() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/attr_url");
+            then.status(500);
+        });
+
+        let config = TEST_CONFIG_VALID.replace(
+            "tag = \"report_move\"\n",
+            "tag = \"report_move\"\nerror_url = \"https://example.com/error\"\n",
+        );
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(&config).nested());
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+        let config = client.rocket().state::<CoreConfig>().unwrap();
+
+        let session_id = config.sessions().create();
+        config.sessions().set_purpose(&session_id, "report_move");
+        let mut state = HashMap::new();
+        state.insert("attr_url".to_string(), json!(server.url("/attr_url")));
+        state.insert(
+            "continuation".to_string(),
+            json!("https://example.com/continuation"),
+        );
+        state.insert("session_id".to_string(), json!(session_id));
+        let state = config.encode_urlstate(state).unwrap();
+
+        let response = client
+            .get(format!("/auth_attr_shim/{}?result=test", state))
+            .dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(
+            response.headers().get_one("Location"),
+            Some("https://example.com/error?reason=plugin_error")
+        );
+    }
+
+    #[test]
+    fn test_attr_shim_rejected() {
+        let server = MockServer::start();
+        let attr_mock = server.mock(|when, then| {
+            when.path("/attr_url");
+            then.status(400);
+        });
+
+        let dir =
+            std::env::temp_dir().join(format!("core-test-shim-rejected-{}", std::process::id()));
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested())
+            .merge(("dead_letters", json!({ "path": dir })));
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+        let config = client.rocket().state::<CoreConfig>().unwrap();
+
+        let mut state = HashMap::new();
+        state.insert("attr_url".to_string(), json!(server.url("/attr_url")));
+        state.insert(
+            "continuation".to_string(),
+            json!("https://example.com/continuation"),
+        );
+        let state = config.encode_urlstate(state).unwrap();
+
+        // Results refused by the plugin count as undelivered
+        let response = client
+            .get(format!("/auth_attr_shim/{}?result=test", state))
+            .dispatch();
+        attr_mock.assert();
+        assert_eq!(response.status(), Status::InternalServerError);
+        let stats = tokio_test::block_on(config.dead_letters().unwrap().stats());
+        assert_eq!(stats.map(|(entries, _)| entries), Some(1));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_tel_shim_settings() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested())
+            .merge(("tel_shim_ttl", 120))
+            .merge((
+                "tel_shim_claims",
+                json!({"environment": "test", "purpose": "default"}),
+            ));
+
+        let config = figment.extract::<CoreConfig>().unwrap();
+
+        let mut method = super::AuthenticationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: "http://auth-test:8000".into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attr_url: false,
+            shim_tel_url: true,
+            shim_schemes: Default::default(),
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
+        };
+        method
+            .tel_shim_claims
+            .insert("purpose".into(), json!("report_move"));
+        assert_eq!(
+            method.continuation_ttl("tel:0123456789", &None, &config),
+            Some(Duration::from_secs(120))
+        );
+
+        let token = method
+            .sign_continuation("tel:0123456789", "session", &config)
+            .unwrap();
+        let payload = token.split('.').nth(1).unwrap();
+        let payload = serde_json::from_slice::<serde_json::Value>(
+            &base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(payload["continuation"], "tel:0123456789");
+        assert_eq!(payload["session_id"], "session");
+        assert_eq!(payload["environment"], "test");
+        assert_eq!(payload["purpose"], "report_move");
+        assert_eq!(
+            payload["exp"].as_u64().unwrap() - payload["iat"].as_u64().unwrap(),
+            120
+        );
+
+        method.tel_shim_ttl = Some(300);
+        assert_eq!(
+            method.continuation_ttl("tel:0123456789", &None, &config),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn test_shim_schemes() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(Toml::string(TEST_CONFIG_VALID).nested())
+            .merge((
+                "ui_shim_urls",
+                json!({"sip": "https://poc.idcontact.test.tweede.golf/sip/"}),
+            ));
+
+        let config = figment.extract::<CoreConfig>().unwrap();
+
+        let method = super::AuthenticationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: "http://auth-test:8000".into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attr_url: false,
+            shim_tel_url: true,
+            shim_schemes: vec!["sip".into()],
+            headers: Default::default(),
+            tls: Default::default(),
+            attribute_mapping: Default::default(),
+            supports_attribute_alternatives: false,
+            supports_session_id: false,
+            supports_failure_url: false,
+            result_key: None,
+            tel_shim_ttl: None,
+            tel_shim_claims: Default::default(),
+            loa: None,
+        };
+
+        assert!(method
+            .parse_continuation("sip:0123456789@example.com", "session", &config)
+            .unwrap()
+            .starts_with("https://poc.idcontact.test.tweede.golf/sip/"));
+        assert!(method
+            .parse_continuation("tel:0123456789", "session", &config)
+            .unwrap()
+            .starts_with("https://poc.idcontact.test.tweede.golf/tel/"));
+        assert_eq!(
+            method
+                .parse_continuation("whatsapp:0123456789", "session", &config)
+                .unwrap(),
+            "whatsapp:0123456789"
+        );
+        assert!(method
+            .continuation_ttl("sip:0123456789@example.com", &None, &config)
+            .is_some());
+        assert!(method
+            .continuation_ttl("whatsapp:0123456789", &None, &config)
+            .is_none());
+    }
 }
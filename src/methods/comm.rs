@@ -1,21 +1,127 @@
-use std::time::Duration;
-
-use super::{Method, Tag};
+use std::time::{Duration, SystemTime};
+
+use super::{Method, PluginClientConfig, PluginHeaders, ProtocolVersion, Tag};
+use crate::{
+    config::Attribute,
+    discovery::Discovery,
+    endpoints::{Canary, Endpoints},
+    error::Error,
+    metrics::PluginCall,
+    sentry::send_traced,
+    tls::PluginTlsConfig,
+};
 use id_contact_proto::{StartCommRequest, StartCommResponse};
-use serde::Deserialize;
+use josekit::{
+    jws::{JwsHeader, JwsSigner},
+    jwt::{self, JwtPayload},
+};
+use rocket::{
+    form::{self, FromFormField, ValueField},
+    Shutdown,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// Longest client url we hand out, as browsers and proxies may truncate or reject longer ones
+const MAX_CLIENT_URL_LENGTH: usize = 8192;
 
 fn default_as_false() -> bool {
     false
 }
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct CommunicationMethod {
     tag: Tag,
     name: String,
     image_path: String,
-    start: String,
+    // Overrides the global asset_base_url for the image of this method
+    asset_base_url: Option<String>,
+    #[serde(alias = "start_primary")]
+    start: Endpoints,
+    // Endpoints of a new version of the plugin, see set_canary_split
+    start_canary: Option<Canary>,
+    // Find plugin instances through service discovery, using start until any are found
+    discovery: Option<Discovery>,
+    #[serde(default)]
+    protocol_version: ProtocolVersion,
     #[serde(default = "default_as_false")]
     disable_attributes_at_start: bool,
+    #[serde(default)]
+    headers: PluginHeaders,
+    // Trust settings for this plugin on top of the global ones
+    #[serde(default)]
+    tls: PluginTlsConfig,
+    #[serde(default = "default_as_false")]
+    supports_requested_attributes: bool,
+    // Send the core session id along, so logs of the plugin can be correlated with core's
+    #[serde(default = "default_as_false")]
+    supports_session_id: bool,
+    // Wrap relayed auth results in a jwt signed by core binding them to their purpose and session,
+    // so the plugin can detect results replayed across purposes
+    #[serde(default = "default_as_false")]
+    supports_result_envelope: bool,
+    // Tenant the method is configured for, to tell its calls apart in statistics
+    #[serde(skip)]
+    tenant: Option<String>,
+}
+
+// Attribute requested for the purpose of a session, with its display metadata if registered
+#[derive(Debug, Serialize)]
+pub struct RequestedAttribute {
+    pub tag: String,
+    #[serde(flatten)]
+    pub metadata: Option<Attribute>,
+}
+
+// Opaque context of a start request, such as a case number, passed on to the comm plugin
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+pub struct RequestContext(Map<String, Value>);
+
+impl RequestContext {
+    // Only small, flat contexts are passed on, so plugins can show them as they are
+    pub fn check(&self, allowed_keys: &Option<Vec<String>>, max_size: usize) -> Result<(), Error> {
+        let size = serde_json::to_string(&self.0)?.len();
+        if size > max_size {
+            log::warn!("Start request context of {} bytes exceeds limit", size);
+            return Err(Error::BadRequest);
+        }
+        for (key, value) in &self.0 {
+            if matches!(value, Value::Null | Value::Array(_) | Value::Object(_)) {
+                log::warn!("Start request context value {} is not a scalar", key);
+                return Err(Error::BadRequest);
+            }
+            if matches!(allowed_keys, Some(allowed) if !allowed.contains(key)) {
+                log::warn!("Start request context key {} not allowed", key);
+                return Err(Error::BadRequest);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Contexts in forms are json encoded
+impl<'v> FromFormField<'v> for RequestContext {
+    fn from_value(field: ValueField<'v>) -> form::Result<'v, Self> {
+        serde_json::from_str(field.value)
+            .map_err(|_| form::Error::validation("invalid context").into())
+    }
+}
+
+// Start request including core-specific extensions of the plugin protocol
+#[derive(Debug, Serialize)]
+struct ExtendedStartCommRequest<'a> {
+    #[serde(flatten)]
+    request: StartCommRequest,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attributes: Option<&'a [RequestedAttribute]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session_id: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    context: Option<&'a RequestContext>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    protocol_version: Option<u32>,
 }
 
 impl Method for CommunicationMethod {
@@ -30,25 +136,159 @@ impl Method for CommunicationMethod {
     fn image_path(&self) -> &str {
         &self.image_path
     }
+
+    fn asset_base_url(&self) -> Option<&str> {
+        self.asset_base_url.as_deref()
+    }
 }
 
 impl CommunicationMethod {
-    // Start a communication session to be composed with an authentication session
-    pub async fn start(&self, purpose: &str) -> Result<StartCommResponse, reqwest::Error> {
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()?;
-
-        Ok(client
-            .post(&format!("{}/start_communication", &self.start))
-            .json(&StartCommRequest {
+    pub fn setup_client(&mut self, config: &PluginClientConfig) -> Result<(), String> {
+        self.headers.setup_client(config, &self.tls)
+    }
+
+    pub fn set_tenant(&mut self, tenant: Option<&str>) {
+        self.tenant = tenant.map(str::to_string);
+    }
+
+    // Point the method at the mock plugins served by core
+    pub fn use_mock(&mut self, mock_url: &str) {
+        self.start = mock_url.into();
+        self.start_canary = None;
+        self.discovery = None;
+    }
+
+    // Client for requests to the plugin, such as the delivery of auth results at its attribute url
+    pub fn client(&self) -> Result<reqwest::Client, reqwest::Error> {
+        self.headers.client()
+    }
+
+    pub fn discovery(&self) -> Option<&Discovery> {
+        self.discovery.as_ref()
+    }
+
+    pub async fn ping(&self) -> Result<(), reqwest::Error> {
+        self.headers.ping(&self.start_url().0).await
+    }
+
+    pub fn canary(&self) -> Option<&Canary> {
+        self.start_canary.as_ref()
+    }
+
+    // Url to start a session at, and whether it is that of the canary
+    fn start_url(&self) -> (String, bool) {
+        match (&self.start_canary, &self.discovery) {
+            (Some(canary), _) if canary.picked() => (canary.endpoints().url().to_string(), true),
+            (_, Some(discovery)) => (discovery.url(self.start.url()), false),
+            _ => (self.start.url().to_string(), false),
+        }
+    }
+
+    pub fn watch_endpoints(&self, interval: Duration, shutdown: &Shutdown) {
+        let canary = self.start_canary.as_ref().map(Canary::endpoints);
+        for endpoints in std::iter::once(&self.start).chain(canary) {
+            if endpoints.is_weighted() {
+                endpoints.clone().watch(
+                    self.headers.clone(),
+                    self.tag.clone(),
+                    interval,
+                    shutdown.clone(),
+                );
+            }
+        }
+    }
+
+    fn call<'a>(&'a self, purpose: &'a str, call: &'static str, canary: bool) -> PluginCall<'a> {
+        PluginCall {
+            tenant: self.tenant.as_deref(),
+            kind: "comm",
+            method: &self.tag,
+            purpose,
+            call,
+            canary,
+        }
+    }
+
+    fn start_request<'a>(
+        &self,
+        purpose: &str,
+        auth_result: Option<String>,
+        attributes: &'a [RequestedAttribute],
+        session_id: &'a str,
+        context: Option<&'a RequestContext>,
+        language: Option<&'a str>,
+    ) -> ExtendedStartCommRequest<'a> {
+        // Plugins not supporting them don't get the requested attributes or session id, and
+        // only plugins of the extended protocol get the context and language
+        let extended = self.protocol_version.extended();
+        ExtendedStartCommRequest {
+            request: StartCommRequest {
                 purpose: purpose.to_string(),
-                auth_result: None,
-            })
-            .send()
-            .await?
-            .json::<StartCommResponse>()
-            .await?)
+                auth_result,
+            },
+            attributes: if self.supports_requested_attributes || extended {
+                Some(attributes)
+            } else {
+                None
+            },
+            session_id: if self.supports_session_id || extended {
+                Some(session_id)
+            } else {
+                None
+            },
+            context: context.filter(|_| extended),
+            language: language.filter(|_| extended),
+            protocol_version: self.protocol_version.announced(),
+        }
+    }
+
+    // Auth result as relayed by core to this plugin, in an envelope if it supports them
+    pub fn envelope(
+        &self,
+        auth_result: &str,
+        purpose: &str,
+        session_id: &str,
+        signer: &dyn JwsSigner,
+    ) -> Result<String, Error> {
+        if !self.supports_result_envelope {
+            return Ok(auth_result.to_string());
+        }
+        let mut payload = JwtPayload::new();
+        payload.set_issued_at(&SystemTime::now());
+        payload.set_expires_at(&(SystemTime::now() + Duration::from_secs(5 * 60)));
+        payload.set_claim("auth_result", Some(auth_result.into()))?;
+        payload.set_claim("purpose", Some(purpose.into()))?;
+        payload.set_claim("session_id", Some(session_id.into()))?;
+        Ok(jwt::encode_with_signer(
+            &payload,
+            &JwsHeader::new(),
+            signer,
+        )?)
+    }
+
+    // Start a communication session to be composed with an authentication session
+    pub async fn start(
+        &self,
+        purpose: &str,
+        attributes: &[RequestedAttribute],
+        session_id: &str,
+        context: Option<&RequestContext>,
+        language: Option<&str>,
+    ) -> Result<StartCommResponse, reqwest::Error> {
+        let client = self.headers.client()?;
+
+        let (url, canary) = self.start_url();
+        let request = client
+            .post(&format!("{}/start_communication", url))
+            .json(&self.start_request(purpose, None, attributes, session_id, context, language));
+        Ok(send_traced(
+            &client,
+            request,
+            self.call(purpose, "start_communication", canary),
+        )
+        .await?
+        .json::<StartCommResponse>()
+        .await?)
     }
 
     // Falback for plugins not supporting attribute reception on startup
@@ -56,19 +296,23 @@ impl CommunicationMethod {
         &self,
         purpose: &str,
         auth_result: &str,
-    ) -> Result<StartCommResponse, reqwest::Error> {
-        let comm_data = self.start(purpose).await?;
+        attributes: &[RequestedAttribute],
+        session_id: &str,
+        context: Option<&RequestContext>,
+        language: Option<&str>,
+    ) -> Result<StartCommResponse, Error> {
+        // Results that can't fit in any client url are refused before a session is started
+        let encoded = urlencoding::encode(auth_result);
+        if encoded.len() + "?result=".len() > MAX_CLIENT_URL_LENGTH {
+            return Err(Error::ClientUrlTooLong(encoded.len()));
+        }
 
-        if let Some(attr_url) = comm_data.attr_url {
-            let client = reqwest::Client::builder()
-                .timeout(Duration::from_secs(5))
-                .build()?;
+        let comm_data = self
+            .start(purpose, attributes, session_id, context, language)
+            .await?;
 
-            client
-                .post(&attr_url)
-                .header("Content-Type", "application/jwt")
-                .body(auth_result.to_string())
-                .send()
+        if let Some(attr_url) = comm_data.attr_url {
+            self.deliver_auth_result(&attr_url, auth_result, purpose)
                 .await?;
 
             Ok(StartCommResponse {
@@ -76,52 +320,108 @@ impl CommunicationMethod {
                 attr_url: None,
             })
         } else {
+            let client_url = if comm_data.client_url.contains('?') {
+                format!("{}&result={}", comm_data.client_url, encoded)
+            } else {
+                format!("{}?result={}", comm_data.client_url, encoded)
+            };
+            if client_url.len() > MAX_CLIENT_URL_LENGTH {
+                return Err(Error::ClientUrlTooLong(client_url.len()));
+            }
+
             Ok(StartCommResponse {
-                client_url: if comm_data.client_url.contains('?') {
-                    format!("{}&result={}", comm_data.client_url, auth_result)
-                } else {
-                    format!("{}?result={}", comm_data.client_url, auth_result)
-                },
+                client_url,
                 attr_url: None,
             })
         }
     }
 
+    // Send authentication results to a running communication session
+    pub async fn deliver_auth_result(
+        &self,
+        attr_url: &str,
+        auth_result: &str,
+        purpose: &str,
+    ) -> Result<(), reqwest::Error> {
+        let client = self.headers.client()?;
+
+        let request = client
+            .post(attr_url)
+            .header("Content-Type", "application/jwt")
+            .body(auth_result.to_string());
+        send_traced(
+            &client,
+            request,
+            self.call(purpose, "deliver_auth_result", false),
+        )
+        .await?
+        .error_for_status()?;
+        Ok(())
+    }
+
     // Start a communication session for which we already have authentication results.
     pub async fn start_with_auth_result(
         &self,
         purpose: &str,
         auth_result: &str,
-    ) -> Result<StartCommResponse, reqwest::Error> {
+        attributes: &[RequestedAttribute],
+        session_id: &str,
+        context: Option<&RequestContext>,
+        language: Option<&str>,
+    ) -> Result<StartCommResponse, Error> {
         if self.disable_attributes_at_start {
             return self
-                .start_with_attributes_fallback(purpose, auth_result)
+                .start_with_attributes_fallback(
+                    purpose,
+                    auth_result,
+                    attributes,
+                    session_id,
+                    context,
+                    language,
+                )
                 .await;
         }
 
-        let client = reqwest::Client::builder()
-            .timeout(Duration::from_secs(5))
-            .build()?;
+        let client = self.headers.client()?;
 
-        Ok(client
-            .post(&format!("{}/start_communication", &self.start))
-            .json(&StartCommRequest {
-                purpose: purpose.to_string(),
-                auth_result: Some(auth_result.to_string()),
-            })
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<StartCommResponse>()
-            .await?)
+        let (url, canary) = self.start_url();
+        let request =
+            client
+                .post(&format!("{}/start_communication", url))
+                .json(&self.start_request(
+                    purpose,
+                    Some(auth_result.to_string()),
+                    attributes,
+                    session_id,
+                    context,
+                    language,
+                ));
+        Ok(send_traced(
+            &client,
+            request,
+            self.call(purpose, "start_communication", canary),
+        )
+        .await?
+        .error_for_status()?
+        .json::<StartCommResponse>()
+        .await?)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::convert::TryFrom;
+
     use httpmock::MockServer;
+    use josekit::{jws::HS256, jwt};
     use serde_json::json;
 
+    use crate::{
+        config::Attribute,
+        error::Error,
+        methods::{PluginHeaders, Tag},
+    };
+
     #[test]
     fn test_start_without_attributes_no_attrurl() {
         let server = MockServer::start();
@@ -139,14 +439,24 @@ mod tests {
         });
 
         let method = super::CommunicationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
         };
 
-        let result = tokio_test::block_on(method.start("something"));
+        let result = tokio_test::block_on(method.start("something", &[], "session", None, None));
 
         start_mock.assert();
         let result = result.unwrap();
@@ -172,14 +482,24 @@ mod tests {
         });
 
         let method = super::CommunicationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
         };
 
-        let result = tokio_test::block_on(method.start("something"));
+        let result = tokio_test::block_on(method.start("something", &[], "session", None, None));
 
         start_mock.assert();
         let result = result.unwrap();
@@ -205,14 +525,31 @@ mod tests {
         });
 
         let method = super::CommunicationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
         };
 
-        let result = tokio_test::block_on(method.start_with_auth_result("something", "test"));
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            "test",
+            &[],
+            "session",
+            None,
+            None,
+        ));
 
         start_mock.assert();
         let result = result.unwrap();
@@ -245,14 +582,31 @@ mod tests {
         });
 
         let method = super::CommunicationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attributes_at_start: true,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
         };
 
-        let result = tokio_test::block_on(method.start_with_auth_result("something", "test"));
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            "test",
+            &[],
+            "session",
+            None,
+            None,
+        ));
 
         start_mock.assert();
         auth_mock.assert();
@@ -278,14 +632,31 @@ mod tests {
         });
 
         let method = super::CommunicationMethod {
-            tag: "test".into(),
+            tag: Tag::try_from("test").unwrap(),
             name: "test".into(),
             image_path: "none".into(),
-            start: server.base_url(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
             disable_attributes_at_start: true,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
         };
 
-        let result = tokio_test::block_on(method.start_with_auth_result("something", "test"));
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            "test",
+            &[],
+            "session",
+            None,
+            None,
+        ));
 
         start_mock.assert();
         let result = result.unwrap();
@@ -295,4 +666,332 @@ mod tests {
         );
         assert_eq!(result.attr_url, None);
     }
+
+    #[test]
+    fn test_start_with_static_headers() {
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_communication")
+                .method(httpmock::Method::POST)
+                .header("Authorization", "Bearer test")
+                .json_body(json!({
+                    "purpose": "something"
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url",
+                }));
+        });
+
+        let method = super::CommunicationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attributes_at_start: false,
+            headers: PluginHeaders::try_from(
+                vec![("Authorization".to_string(), "Bearer test".to_string())]
+                    .into_iter()
+                    .collect::<std::collections::HashMap<_, _>>(),
+            )
+            .unwrap(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
+        };
+
+        let result = tokio_test::block_on(method.start("something", &[], "session", None, None));
+
+        start_mock.assert();
+        assert_eq!(result.unwrap().client_url, "https://example.com/client_url");
+    }
+
+    #[test]
+    fn test_static_headers_log_hiding() {
+        let headers = PluginHeaders::try_from(
+            vec![("Authorization".to_string(), "Bearer test".to_string())]
+                .into_iter()
+                .collect::<std::collections::HashMap<_, _>>(),
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", headers), "{\"authorization\"}");
+    }
+
+    #[test]
+    fn test_auth_result_fallback_encoding() {
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_communication")
+                .method(httpmock::Method::POST)
+                .json_body(json!({
+                    "purpose": "something",
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url?lang=nl",
+                }));
+        });
+
+        let method = super::CommunicationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attributes_at_start: true,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
+        };
+
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            "te&st=#",
+            &[],
+            "session",
+            None,
+            None,
+        ));
+        assert_eq!(
+            result.unwrap().client_url,
+            "https://example.com/client_url?lang=nl&result=te%26st%3D%23"
+        );
+
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            &"a".repeat(super::MAX_CLIENT_URL_LENGTH),
+            &[],
+            "session",
+            None,
+            None,
+        ));
+        assert!(matches!(result, Err(Error::ClientUrlTooLong(_))));
+        // No comm session was started for it
+        start_mock.assert_hits(1);
+    }
+
+    #[test]
+    fn test_start_requested_attributes() {
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_communication")
+                .method(httpmock::Method::POST)
+                .json_body(json!({
+                    "purpose": "something",
+                    "attributes": [
+                        {"tag": "email", "name": "E-mail"},
+                        {"tag": "phone"},
+                    ],
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url",
+                }));
+        });
+
+        let method = super::CommunicationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: true,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
+        };
+
+        let attributes = vec![
+            super::RequestedAttribute {
+                tag: "email".into(),
+                metadata: Some(Attribute {
+                    name: "E-mail".into(),
+                    description: None,
+                }),
+            },
+            super::RequestedAttribute {
+                tag: "phone".into(),
+                metadata: None,
+            },
+        ];
+        let result =
+            tokio_test::block_on(method.start("something", &attributes, "session", None, None));
+
+        start_mock.assert();
+        assert_eq!(result.unwrap().client_url, "https://example.com/client_url");
+    }
+
+    #[test]
+    fn test_start_session_id() {
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_communication")
+                .method(httpmock::Method::POST)
+                .json_body(json!({
+                    "purpose": "something",
+                    "auth_result": "test",
+                    "session_id": "session",
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url",
+                }));
+        });
+
+        let method = super::CommunicationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: true,
+            supports_result_envelope: false,
+            tenant: None,
+        };
+
+        // Plugins of the base protocol get no context or language, even if passed
+        let context: super::RequestContext =
+            serde_json::from_value(json!({"case_number": 1234})).unwrap();
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            "test",
+            &[],
+            "session",
+            Some(&context),
+            Some("nl"),
+        ));
+
+        start_mock.assert();
+        assert_eq!(result.unwrap().client_url, "https://example.com/client_url");
+    }
+
+    #[test]
+    fn test_start_protocol_version() {
+        let server = MockServer::start();
+        let start_mock = server.mock(|when, then| {
+            when.path("/start_communication")
+                .method(httpmock::Method::POST)
+                .json_body(json!({
+                    "purpose": "something",
+                    "auth_result": "test",
+                    "attributes": [],
+                    "session_id": "session",
+                    "context": {"case_number": 1234},
+                    "language": "nl",
+                    "protocol_version": 2,
+                }));
+            then.status(200)
+                .header("Content-Type", "application/json")
+                .json_body(json!({
+                    "client_url": "https://example.com/client_url",
+                }));
+        });
+
+        // Version 2 plugins get all extensions without setting the supports_* flags
+        let method = super::CommunicationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: server.base_url().into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: serde_json::from_value(json!(2)).unwrap(),
+            disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
+        };
+
+        let context: super::RequestContext =
+            serde_json::from_value(json!({"case_number": 1234})).unwrap();
+        let result = tokio_test::block_on(method.start_with_auth_result(
+            "something",
+            "test",
+            &[],
+            "session",
+            Some(&context),
+            Some("nl"),
+        ));
+
+        start_mock.assert();
+        assert_eq!(result.unwrap().client_url, "https://example.com/client_url");
+        assert!(serde_json::from_value::<super::ProtocolVersion>(json!(3)).is_err());
+        assert!(serde_json::from_value::<super::ProtocolVersion>(json!(0)).is_err());
+    }
+
+    #[test]
+    fn test_result_envelope() {
+        let mut method = super::CommunicationMethod {
+            tag: Tag::try_from("test").unwrap(),
+            name: "test".into(),
+            image_path: "none".into(),
+            asset_base_url: None,
+            start: "https://comm.test".into(),
+            start_canary: None,
+            discovery: None,
+            protocol_version: Default::default(),
+            disable_attributes_at_start: false,
+            headers: Default::default(),
+            tls: Default::default(),
+            supports_requested_attributes: false,
+            supports_session_id: false,
+            supports_result_envelope: false,
+            tenant: None,
+        };
+        let signer = HS256
+            .signer_from_bytes(b"envelope_secret_1234567890")
+            .unwrap();
+        let verifier = HS256
+            .verifier_from_bytes(b"envelope_secret_1234567890")
+            .unwrap();
+
+        // Plugins not supporting envelopes get the result as is
+        let result = method
+            .envelope("result", "report_move", "session", &signer)
+            .unwrap();
+        assert_eq!(result, "result");
+
+        method.supports_result_envelope = true;
+        let envelope = method
+            .envelope("result", "report_move", "session", &signer)
+            .unwrap();
+        let (payload, _) = jwt::decode_with_verifier(&envelope, &verifier).unwrap();
+        assert_eq!(payload.claim("auth_result"), Some(&json!("result")));
+        assert_eq!(payload.claim("purpose"), Some(&json!("report_move")));
+        assert_eq!(payload.claim("session_id"), Some(&json!("session")));
+        assert!(payload.expires_at().is_some());
+    }
 }
@@ -1,8 +1,12 @@
 use std::collections::HashMap;
 
 use crate::methods::{Method, Tag};
-use crate::{config::CoreConfig, error::Error};
-use rocket::{serde::json::Json, State};
+use crate::{
+    apikey::ApiKey,
+    config::{Attribute, CoreConfig},
+    error::Error,
+};
+use rocket::serde::json::Json;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -13,18 +17,19 @@ struct MethodProperties {
 }
 
 impl MethodProperties {
-    fn filter_methods_by_tags<'a, T: Method, I: Iterator<Item = &'a String>>(
+    fn filter_methods_by_tags<'a, T: Method, I: Iterator<Item = &'a Tag>>(
         tags: I,
-        methods: &HashMap<String, T>,
+        methods: &HashMap<Tag, T>,
+        config: &CoreConfig,
     ) -> Result<Vec<MethodProperties>, Error> {
         tags.map(|t| {
             let method = methods
                 .get(t)
-                .ok_or_else(|| Error::NoSuchMethod(t.clone()))?;
+                .ok_or_else(|| Error::NoSuchMethod(t.to_string()))?;
             Ok(MethodProperties {
-                tag: String::from(method.tag()),
+                tag: method.tag().clone(),
                 name: String::from(method.name()),
-                image_path: String::from(method.image_path()),
+                image_path: config.image_url(method),
             })
         })
         .collect()
@@ -35,22 +40,33 @@ impl MethodProperties {
 pub struct SessionOptions {
     auth_methods: Vec<MethodProperties>,
     comm_methods: Vec<MethodProperties>,
+    // Set for purposes to be removed, which requestors should move away from
+    #[serde(default)]
+    deprecated: bool,
 }
 
 type AllSessionOptions = HashMap<String, SessionOptions>;
 
 #[get("/session_options")]
-pub fn all_session_options(config: &State<CoreConfig>) -> Result<Json<AllSessionOptions>, Error> {
+pub fn all_session_options(
+    api_key: ApiKey,
+    config: &CoreConfig,
+) -> Result<Json<AllSessionOptions>, Error> {
     let mut all_options: AllSessionOptions = HashMap::new();
 
     for (name, purpose) in &config.purposes {
+        if !api_key.allows(name) {
+            continue;
+        }
         let auth_methods = MethodProperties::filter_methods_by_tags(
-            purpose.allowed_auth.iter(),
-            &config.auth_methods,
+            config.allowed_auth(purpose).iter(),
+            &config.all_auth_methods(),
+            config,
         )?;
         let comm_methods = MethodProperties::filter_methods_by_tags(
-            purpose.allowed_comm.iter(),
-            &config.comm_methods,
+            config.allowed_comm(purpose).iter(),
+            &config.all_comm_methods(),
+            config,
         )?;
 
         all_options.insert(
@@ -58,6 +74,7 @@ pub fn all_session_options(config: &State<CoreConfig>) -> Result<Json<AllSession
             SessionOptions {
                 auth_methods,
                 comm_methods,
+                deprecated: purpose.deprecated,
             },
         );
     }
@@ -68,33 +85,45 @@ pub fn all_session_options(config: &State<CoreConfig>) -> Result<Json<AllSession
 #[get("/session_options/<purpose>")]
 pub fn session_options(
     purpose: String,
-    config: &State<CoreConfig>,
+    api_key: ApiKey,
+    config: &CoreConfig,
 ) -> Result<Json<SessionOptions>, Error> {
-    let purpose = config
-        .purposes
-        .get(&purpose)
-        .ok_or_else(|| Error::NoSuchPurpose(purpose.clone()))?;
+    let purpose = config.purpose(&purpose)?;
+    api_key.check(&purpose.tag)?;
     let auth_methods = MethodProperties::filter_methods_by_tags(
-        purpose.allowed_auth.iter(),
-        &config.auth_methods,
+        config.allowed_auth(purpose).iter(),
+        &config.all_auth_methods(),
+        config,
     )?;
     let comm_methods = MethodProperties::filter_methods_by_tags(
-        purpose.allowed_comm.iter(),
-        &config.comm_methods,
+        config.allowed_comm(purpose).iter(),
+        &config.all_comm_methods(),
+        config,
     )?;
 
     Ok(Json(SessionOptions {
         auth_methods,
         comm_methods,
+        deprecated: purpose.deprecated,
     }))
 }
 
+#[get("/attributes")]
+pub fn attributes(config: &CoreConfig) -> Json<HashMap<String, Attribute>> {
+    Json(config.attributes.clone().unwrap_or_default())
+}
+
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use rocket::{http::Status, local::blocking::Client};
 
     use super::SessionOptions;
-    use crate::setup_routes;
+    use crate::{
+        config::{Attribute, CoreConfig},
+        setup_routes,
+    };
     use figment::providers::{Format, Toml};
     use rocket::figment::Figment;
 
@@ -102,6 +131,7 @@ mod tests {
 [global]
 server_url = "https://core.idcontact.test.tweede.golf"
 internal_url = "http://core:8000"
+public_internal_routes = true
 internal_secret = "sample_secret_1234567890178901237890"
 ui_tel_url = "https://poc.idcontact.test.tweede.golf/tel/"
 
@@ -228,4 +258,186 @@ allowed_comm = [ "call" ]
         let response = client.get("/session_options/does_not_exist").dispatch();
         assert_ne!(response.status(), Status::Ok);
     }
+
+    #[test]
+    fn test_attributes() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(
+                Toml::string(&format!(
+                    r#"{}
+[global.attributes.email]
+name = "E-mailadres"
+"#,
+                    TEST_CONFIG_VALID
+                ))
+                .nested(),
+            );
+
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+
+        let response = client.get("/attributes").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response =
+            serde_json::from_slice::<HashMap<String, Attribute>>(&response.into_bytes().unwrap())
+                .unwrap();
+        assert_eq!(response.len(), 1);
+        assert_eq!(response["email"].name, "E-mailadres");
+        assert_eq!(response["email"].description, None);
+    }
+
+    #[test]
+    fn test_min_loa() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(
+                Toml::string(&format!(
+                    r#"{}
+[[global.auth_methods]]
+tag = "eherkenning"
+name = "Gebruik eHerkenning"
+image_path = "/static/eherkenning.svg"
+start = "http://auth-test:8000"
+loa = "high"
+
+[[global.purposes]]
+tag = "register_company"
+attributes = [ "email" ]
+allowed_auth = [ "*" ]
+allowed_comm = [ "call" ]
+min_loa = "substantial"
+"#,
+                    TEST_CONFIG_VALID
+                ))
+                .nested(),
+            );
+
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+
+        let response = client.get("/session_options/register_company").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response =
+            serde_json::from_slice::<SessionOptions>(&response.into_bytes().unwrap()).unwrap();
+        assert_eq!(response.auth_methods.len(), 1);
+        assert_eq!(response.auth_methods[0].tag, "eherkenning");
+
+        // Purposes without a requirement still offer every method
+        let response = client.get("/session_options/report_move").dispatch();
+        let response =
+            serde_json::from_slice::<SessionOptions>(&response.into_bytes().unwrap()).unwrap();
+        assert_eq!(response.auth_methods.len(), 3);
+
+        let config = client.rocket().state::<CoreConfig>().unwrap();
+        let purpose = config.purpose("register_company").unwrap();
+        assert!(config.auth_method(purpose, "irma").is_err());
+        assert!(config.auth_method(purpose, "eherkenning").is_ok());
+    }
+
+    #[test]
+    fn test_asset_base_url() {
+        let figment = Figment::from(rocket::Config::default())
+            .select(rocket::Config::DEFAULT_PROFILE)
+            .merge(
+                Toml::string(&format!(
+                    r#"{}
+[[global.auth_methods]]
+tag = "eherkenning"
+name = "Gebruik eHerkenning"
+image_path = "eherkenning.svg"
+asset_base_url = "https://assets.example.com"
+start = "http://auth-test:8000"
+
+[[global.auth_methods]]
+tag = "idin"
+name = "Gebruik iDIN"
+image_path = "https://example.com/idin.svg"
+start = "http://auth-test:8000"
+"#,
+                    TEST_CONFIG_VALID
+                ))
+                .nested(),
+            )
+            .merge(("asset_base_url", "https://cdn.example.com/core/"));
+
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+
+        let response = client.get("/session_options/report_move").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response =
+            serde_json::from_slice::<SessionOptions>(&response.into_bytes().unwrap()).unwrap();
+        let image = |tag: &str| {
+            response
+                .auth_methods
+                .iter()
+                .find(|m| m.tag == tag)
+                .map(|m| m.image_path.clone())
+                .unwrap()
+        };
+        assert_eq!(
+            image("irma"),
+            "https://cdn.example.com/core/static/irma.svg"
+        );
+        assert_eq!(
+            image("eherkenning"),
+            "https://assets.example.com/eherkenning.svg"
+        );
+        assert_eq!(image("idin"), "https://example.com/idin.svg");
+    }
+
+    #[test]
+    fn test_purpose_aliases() {
+        let figment_with = |purposes: &str| {
+            Figment::from(rocket::Config::default())
+                .select(rocket::Config::DEFAULT_PROFILE)
+                .merge(Toml::string(&format!("{}{}", TEST_CONFIG_VALID, purposes)).nested())
+        };
+        let figment = figment_with(
+            r#"
+[[global.purposes]]
+tag = "report_relocation"
+aliases = [ "report_address" ]
+attributes = [ "email" ]
+allowed_auth = [ "irma" ]
+allowed_comm = [ "call" ]
+deprecated = true
+"#,
+        );
+
+        let client = Client::tracked(setup_routes(rocket::custom(figment))).unwrap();
+
+        let response = client.get("/session_options/report_address").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response =
+            serde_json::from_slice::<SessionOptions>(&response.into_bytes().unwrap()).unwrap();
+        assert!(response.deprecated);
+        assert_eq!(response.auth_methods.len(), 1);
+
+        let response = client.get("/session_options").dispatch();
+        let response = serde_json::from_slice::<HashMap<String, SessionOptions>>(
+            &response.into_bytes().unwrap(),
+        )
+        .unwrap();
+        assert!(response["report_relocation"].deprecated);
+        assert!(!response["report_move"].deprecated);
+        assert!(!response.contains_key("report_address"));
+
+        let config = client.rocket().state::<CoreConfig>().unwrap();
+        assert_eq!(
+            config.purpose("report_address").unwrap().tag,
+            "report_relocation"
+        );
+
+        // Aliases can't shadow another purpose
+        let figment = figment_with(
+            r#"
+[[global.purposes]]
+tag = "report_relocation"
+aliases = [ "report_move" ]
+attributes = [ "email" ]
+allowed_auth = [ "irma" ]
+allowed_comm = [ "call" ]
+"#,
+        );
+        assert!(figment.extract::<CoreConfig>().is_err());
+    }
 }
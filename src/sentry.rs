@@ -0,0 +1,563 @@
+use std::{
+    io::Write,
+    net::IpAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    clientip::{client_ip, current_client_ip},
+    config::CoreConfig,
+    logging::{current_request_id, request_id, LogConfig},
+    metrics::{self, PluginCall},
+};
+use ::sentry::{
+    protocol::{Breadcrumb, Event, IpAddress, Map, SpanStatus, User, Value},
+    ClientInitGuard, ClientOptions, Hub, Level, Transaction, TransactionContext,
+};
+use reqwest::header::HeaderValue;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::{Method, Status},
+    Request, Response,
+};
+
+// Logger printing through env_logger, and reporting errors to sentry
+pub struct SentryLogger {
+    inner: env_logger::Logger,
+}
+
+impl SentryLogger {
+    pub fn init(config: &LogConfig) {
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(config.level.0);
+        for (target, level) in &config.filters {
+            builder.filter_module(target, level.0);
+        }
+        // Filters from RUST_LOG take precedence over the configuration
+        builder.parse_env(env_logger::Env::default());
+        if config.json {
+            builder.format(|buf, record| {
+                let line = serde_json::json!({
+                    "timestamp": buf.timestamp().to_string(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "request_id": current_request_id(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            });
+        } else {
+            builder.format(|buf, record| match current_request_id() {
+                Some(id) => writeln!(
+                    buf,
+                    "[{} {:5} {}] [{}] {}",
+                    buf.timestamp(),
+                    record.level(),
+                    record.target(),
+                    id,
+                    record.args()
+                ),
+                None => writeln!(
+                    buf,
+                    "[{} {:5} {}] {}",
+                    buf.timestamp(),
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ),
+            });
+        }
+        let inner = builder.build();
+        let max_level = inner.filter();
+        log::set_boxed_logger(Box::new(SentryLogger { inner }))
+            .map(|()| log::set_max_level(max_level))
+            .expect("Could not set up logger");
+    }
+}
+
+impl log::Log for SentryLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if record.level() == log::Level::Error && self.enabled(record.metadata()) {
+            ::sentry::with_scope(
+                |scope| {
+                    if let Some(id) = current_request_id() {
+                        scope.set_tag("request_id", id);
+                    }
+                    if let Some(ip) = current_client_ip() {
+                        scope.set_user(Some(client_user(ip)));
+                    }
+                },
+                || ::sentry::capture_message(&record.args().to_string(), Level::Error),
+            );
+        }
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Keys of headers, extra data and breadcrumb data that are always stripped from events
+const DEFAULT_SCRUB_RULES: &[&str] = &[
+    "authorization",
+    "cookie",
+    "token",
+    "secret",
+    "password",
+    "key",
+    "result",
+    "body",
+];
+
+// Strip query strings from all urls in a text, as these may carry attributes
+fn strip_queries(text: &str) -> String {
+    text.split(' ')
+        .map(|word| match word.find('?') {
+            Some(i) if word.contains("://") || word.starts_with('/') => &word[..i],
+            _ => word,
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, Clone)]
+pub struct Scrubber {
+    rules: Vec<String>,
+}
+
+impl Scrubber {
+    pub fn new(extra_rules: &[String]) -> Self {
+        Scrubber {
+            rules: DEFAULT_SCRUB_RULES
+                .iter()
+                .map(|rule| rule.to_string())
+                .chain(extra_rules.iter().map(|rule| rule.to_lowercase()))
+                .collect(),
+        }
+    }
+
+    fn is_sensitive(&self, key: &str) -> bool {
+        let key = key.to_lowercase();
+        self.rules.iter().any(|rule| key.contains(rule.as_str()))
+    }
+
+    fn scrub_map(&self, map: &mut Map<String, Value>) {
+        map.retain(|key, _| !self.is_sensitive(key));
+        for value in map.values_mut() {
+            if let Value::String(text) = value {
+                *text = strip_queries(text);
+            }
+        }
+    }
+
+    pub fn scrub_breadcrumb(&self, mut breadcrumb: Breadcrumb) -> Breadcrumb {
+        breadcrumb.message = breadcrumb.message.map(|m| strip_queries(&m));
+        self.scrub_map(&mut breadcrumb.data);
+        breadcrumb
+    }
+
+    pub fn scrub_event(&self, mut event: Event<'static>) -> Event<'static> {
+        if let Some(request) = &mut event.request {
+            if let Some(url) = &mut request.url {
+                url.set_query(None);
+                url.set_fragment(None);
+            }
+            request.query_string = None;
+            request.data = None;
+            request.cookies = None;
+            request.headers.retain(|key, _| !self.is_sensitive(key));
+            request.env.clear();
+        }
+
+        event.message = event.message.map(|m| strip_queries(&m));
+        if let Some(logentry) = &mut event.logentry {
+            logentry.message = strip_queries(&logentry.message);
+            logentry.params.clear();
+        }
+        for exception in event.exception.values.iter_mut() {
+            exception.value = exception.value.as_deref().map(strip_queries);
+        }
+        event.tags.retain(|key, _| !self.is_sensitive(key));
+        self.scrub_map(&mut event.extra);
+        event.breadcrumbs.values = event
+            .breadcrumbs
+            .values
+            .drain(..)
+            .map(|breadcrumb| self.scrub_breadcrumb(breadcrumb))
+            .collect();
+        event
+    }
+}
+
+fn span_status(code: u16) -> SpanStatus {
+    match code {
+        0..=399 => SpanStatus::Ok,
+        401 => SpanStatus::Unauthenticated,
+        403 => SpanStatus::PermissionDenied,
+        404 => SpanStatus::NotFound,
+        429 => SpanStatus::ResourceExhausted,
+        400..=499 => SpanStatus::InvalidArgument,
+        503 => SpanStatus::Unavailable,
+        _ => SpanStatus::InternalError,
+    }
+}
+
+// Breadcrumb describing a plugin call. Only the host is kept, as paths and queries may carry state.
+fn plugin_breadcrumb(
+    method: &reqwest::Method,
+    url: &reqwest::Url,
+    status: Option<u16>,
+    duration: Duration,
+) -> Breadcrumb {
+    let mut data = Map::new();
+    data.insert("method".into(), Value::from(method.as_str()));
+    data.insert(
+        "host".into(),
+        Value::from(url.host_str().unwrap_or_default()),
+    );
+    if let Some(status) = status {
+        data.insert("status_code".into(), Value::from(status));
+    }
+    data.insert(
+        "duration_ms".into(),
+        Value::from(duration.as_millis() as u64),
+    );
+    Breadcrumb {
+        ty: "http".into(),
+        category: Some("plugin".into()),
+        level: match status {
+            Some(status) if status < 400 => Level::Info,
+            _ => Level::Error,
+        },
+        data,
+        ..Default::default()
+    }
+}
+
+// User of an event, identified by the client address only
+fn client_user(ip: IpAddr) -> User {
+    User {
+        ip_address: Some(IpAddress::Exact(ip)),
+        ..Default::default()
+    }
+}
+
+// Breadcrumb describing a request answered by a catcher, for the event reported on 500s.
+// Like transactions, it names the route rather than the path.
+pub fn caught_breadcrumb(status: Status, request: &Request<'_>) -> Breadcrumb {
+    let mut data = Map::new();
+    data.insert("method".into(), Value::from(request.method().as_str()));
+    data.insert(
+        "route".into(),
+        Value::from(
+            request
+                .route()
+                .map(|route| route.uri.as_str())
+                .unwrap_or("<unknown>"),
+        ),
+    );
+    data.insert("status_code".into(), Value::from(status.code));
+    data.insert("request_id".into(), Value::from(request_id(request)));
+    Breadcrumb {
+        ty: "http".into(),
+        category: Some("catcher".into()),
+        level: if status.code >= 500 {
+            Level::Error
+        } else {
+            Level::Warning
+        },
+        data,
+        ..Default::default()
+    }
+}
+
+// Send a request to a plugin, recorded as a span of the transaction active on the current hub,
+// as a breadcrumb for later events and in the metrics of the plugin
+pub async fn send_traced(
+    client: &reqwest::Client,
+    request: reqwest::RequestBuilder,
+    call: PluginCall<'_>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let mut request = request.build()?;
+    if let Some(id) = current_request_id() {
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            request.headers_mut().insert("X-Request-Id", value);
+        }
+    }
+    let span = ::sentry::configure_scope(|scope| scope.get_span()).map(|parent| {
+        let mut url = request.url().clone();
+        url.set_query(None);
+        parent.start_child("http.client", &format!("{} {}", request.method(), url))
+    });
+
+    let method = request.method().clone();
+    let url = request.url().clone();
+    let start = Instant::now();
+    let response = client.execute(request).await;
+    metrics::record(&call, start.elapsed(), metrics::error_class(&response));
+
+    ::sentry::add_breadcrumb(plugin_breadcrumb(
+        &method,
+        &url,
+        response.as_ref().ok().map(|r| r.status().as_u16()),
+        start.elapsed(),
+    ));
+    if let Some(span) = span {
+        span.set_status(match &response {
+            Ok(response) => span_status(response.status().as_u16()),
+            Err(e) if e.is_timeout() => SpanStatus::DeadlineExceeded,
+            Err(_) => SpanStatus::UnknownError,
+        });
+        span.finish();
+    }
+    response
+}
+
+// Name of the route matching a request, so path parameters don't end up in transaction names
+fn route_name(method: Method, path: &str, routes: &[(Method, &str)]) -> String {
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    routes
+        .iter()
+        .filter(|(route_method, _)| *route_method == method)
+        .map(|(_, uri)| uri.split('?').next().unwrap_or_default())
+        .find(|uri| {
+            let template: Vec<&str> = uri.split('/').filter(|s| !s.is_empty()).collect();
+            template.len() == segments.len()
+                && template
+                    .iter()
+                    .zip(&segments)
+                    .all(|(t, s)| t.starts_with('<') || t == s)
+        })
+        .map(|uri| format!("{} {}", method, uri))
+        .unwrap_or_else(|| format!("{} <unknown>", method))
+}
+
+// Transaction of the request currently being handled, if any
+struct RequestTransaction(Option<Transaction>);
+
+// Hub of a request, so concurrent requests don't share spans and tags of the process hub
+struct RequestHub(Arc<Hub>);
+
+// Hub to run the handler of a request in, so events and plugin spans end up in its transaction
+pub fn request_hub(request: &Request<'_>) -> Arc<Hub> {
+    request.local_cache(|| RequestHub(Hub::current())).0.clone()
+}
+
+pub struct SentryFairing {
+    _guard: ClientInitGuard,
+}
+
+impl SentryFairing {
+    pub fn new(dsn: &str, name: &str, config: &CoreConfig) -> Self {
+        let scrubber = Arc::new(Scrubber::new(config.sentry_scrub()));
+        let breadcrumb_scrubber = scrubber.clone();
+        let guard = ::sentry::init((
+            dsn,
+            ClientOptions {
+                release: ::sentry::release_name!(),
+                send_default_pii: false,
+                traces_sample_rate: config.traces_sample_rate(),
+                environment: config.environment().map(|e| e.to_string().into()),
+                server_name: config.server_name().map(|n| n.to_string().into()),
+                before_send: Some(Arc::new(move |event| Some(scrubber.scrub_event(event)))),
+                before_breadcrumb: Some(Arc::new(move |breadcrumb| {
+                    Some(breadcrumb_scrubber.scrub_breadcrumb(breadcrumb))
+                })),
+                ..Default::default()
+            },
+        ));
+        ::sentry::configure_scope(|scope| scope.set_tag("service", name));
+        SentryFairing { _guard: guard }
+    }
+}
+
+#[rocket::async_trait]
+impl Fairing for SentryFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Sentry",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut rocket::Data<'_>) {
+        let routes = request
+            .rocket()
+            .routes()
+            .map(|route| (route.method, route.uri.as_str()))
+            .collect::<Vec<_>>();
+        let name = route_name(request.method(), request.uri().path().as_str(), &routes);
+        // Continue traces started by the requestor
+        let headers = request
+            .headers()
+            .get_one("sentry-trace")
+            .map(|trace| ("sentry-trace", trace));
+        let hub = Arc::new(Hub::new_from_top(Hub::current()));
+        let transaction = hub.start_transaction(TransactionContext::continue_from_headers(
+            &name,
+            "http.server",
+            headers,
+        ));
+        hub.configure_scope(|scope| scope.set_span(Some(transaction.clone().into())));
+        request.local_cache(|| RequestHub(hub));
+        request.local_cache(|| RequestTransaction(Some(transaction)));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let hub = request_hub(request);
+        if let RequestTransaction(Some(transaction)) =
+            request.local_cache(|| RequestTransaction(None))
+        {
+            transaction.set_status(span_status(response.status().code));
+            transaction.clone().finish();
+        }
+
+        if response.status().code >= 500 {
+            // Report the route rather than the path, as path segments can contain url state
+            let route = request
+                .route()
+                .map(|route| route.uri.as_str().to_string())
+                .unwrap_or_default();
+            hub.with_scope(
+                |scope| {
+                    scope.set_tag("request_id", request_id(request));
+                    if let Some(ip) = client_ip(request) {
+                        scope.set_user(Some(client_user(ip)));
+                    }
+                },
+                || {
+                    hub.capture_message(
+                        &format!(
+                            "{} {} returned {}",
+                            request.method(),
+                            route,
+                            response.status()
+                        ),
+                        Level::Error,
+                    )
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{plugin_breadcrumb, route_name, strip_queries, Scrubber};
+    use ::sentry::protocol::{Breadcrumb, Event, Request, Value};
+    use rocket::http::Method;
+
+    #[test]
+    fn test_plugin_breadcrumb() {
+        let url = "https://comm.example.com/attr/ey.ey.sig?result=ey"
+            .parse()
+            .unwrap();
+        let breadcrumb = plugin_breadcrumb(
+            &reqwest::Method::POST,
+            &url,
+            Some(500),
+            std::time::Duration::from_millis(120),
+        );
+        assert_eq!(breadcrumb.ty, "http");
+        assert_eq!(breadcrumb.level, ::sentry::Level::Error);
+        assert_eq!(
+            breadcrumb.data.get("host"),
+            Some(&Value::from("comm.example.com"))
+        );
+        assert_eq!(breadcrumb.data.get("status_code"), Some(&Value::from(500)));
+        assert_eq!(breadcrumb.data.get("duration_ms"), Some(&Value::from(120)));
+        assert!(!format!("{:?}", breadcrumb).contains("result"));
+    }
+
+    #[test]
+    fn test_route_name() {
+        let routes = vec![
+            (Method::Get, "/session_options/<purpose>"),
+            (Method::Post, "/start"),
+            (Method::Get, "/auth_attr_shim/<state>?<result>"),
+        ];
+        assert_eq!(
+            route_name(Method::Get, "/session_options/report_move", &routes),
+            "GET /session_options/<purpose>"
+        );
+        assert_eq!(
+            route_name(Method::Get, "/auth_attr_shim/ey.ey.sig", &routes),
+            "GET /auth_attr_shim/<state>"
+        );
+        assert_eq!(route_name(Method::Post, "/start", &routes), "POST /start");
+        assert_eq!(route_name(Method::Get, "/start", &routes), "GET <unknown>");
+    }
+
+    #[test]
+    fn test_strip_queries() {
+        assert_eq!(
+            strip_queries("GET https://example.com/shim?result=ey.ey.sig failed"),
+            "GET https://example.com/shim failed"
+        );
+        assert_eq!(
+            strip_queries("/auth_attr_shim/ey?result=ey"),
+            "/auth_attr_shim/ey"
+        );
+        assert_eq!(strip_queries("Is this a question?"), "Is this a question?");
+    }
+
+    #[test]
+    fn test_scrub_event() {
+        let scrubber = Scrubber::new(&["X-Custom".into()]);
+
+        let mut event = Event::default();
+        let mut request = Request {
+            url: Some(
+                "https://core.example.com/auth_attr_shim/state?result=ey"
+                    .parse()
+                    .unwrap(),
+            ),
+            query_string: Some("result=ey".into()),
+            data: Some("{\"attributes\": {}}".into()),
+            ..Default::default()
+        };
+        request
+            .headers
+            .insert("Authorization".into(), "Bearer abc".into());
+        request.headers.insert("X-Custom-Id".into(), "123".into());
+        request.headers.insert("Accept".into(), "*/*".into());
+        event.request = Some(request);
+        event.message = Some("Could not post to https://example.com/attr?x=1".into());
+        event
+            .extra
+            .insert("internal_secret".into(), Value::from("very secret"));
+        let mut breadcrumb = Breadcrumb::default();
+        breadcrumb.data.insert("body".into(), Value::from("..."));
+        breadcrumb
+            .data
+            .insert("url".into(), Value::from("https://example.com/?result=ey"));
+        event.breadcrumbs.values.push(breadcrumb);
+
+        let event = scrubber.scrub_event(event);
+        let request = event.request.unwrap();
+        assert_eq!(
+            request.url.unwrap().as_str(),
+            "https://core.example.com/auth_attr_shim/state"
+        );
+        assert_eq!(request.query_string, None);
+        assert_eq!(request.data, None);
+        assert_eq!(request.headers.keys().collect::<Vec<_>>(), vec!["Accept"]);
+        assert_eq!(
+            event.message.unwrap(),
+            "Could not post to https://example.com/attr"
+        );
+        assert!(event.extra.is_empty());
+        let breadcrumb = &event.breadcrumbs.values[0];
+        assert_eq!(breadcrumb.data.get("body"), None);
+        assert_eq!(
+            breadcrumb.data.get("url"),
+            Some(&Value::from("https://example.com/"))
+        );
+    }
+}
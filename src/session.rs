@@ -0,0 +1,1198 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    admin::AdminToken,
+    audit::AuditEvent,
+    config::{AuthStep, CoreConfig},
+    error::Error,
+    idgen::{IdKind, Ids},
+    methods::{RequestContext, Tag},
+    metrics,
+};
+use josekit::jwt;
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    serde::json::Json,
+    tokio::sync::{
+        broadcast::{self, error::RecvError},
+        mpsc::{error::TrySendError, Sender},
+    },
+    Orbit, Request, Rocket,
+};
+use serde::{Deserialize, Serialize};
+
+// Sessions are kept as long as the continuation of an authentication session remains valid,
+// unless a different retention is configured
+pub const SESSION_TTL: Duration = Duration::from_secs(60 * 60);
+
+// Lifecycle events of a session, as far as they pass through core
+#[derive(Debug, Clone, Serialize, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SessionEvent {
+    Started {
+        purpose: String,
+        auth_method: Option<Tag>,
+        comm_method: Option<Tag>,
+        // Opaque state of the requestor, for correlating events with its start request
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<String>,
+    },
+    StartFailed,
+    AuthResultDelivered {
+        success: bool,
+    },
+    AuthResultReceived,
+    CommSelected {
+        comm_method: Tag,
+        success: bool,
+    },
+    AuthStepStarted {
+        auth_method: Tag,
+        success: bool,
+    },
+    AuthFailed {
+        reason: AuthFailure,
+    },
+    AuthRetried {
+        auth_method: Tag,
+        success: bool,
+    },
+}
+
+// Reason of an authentication plugin sending the user back without a result
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFailure {
+    Cancelled,
+    Failed,
+}
+
+// Communication session waiting for authentication results to be delivered later
+#[derive(Debug, Clone)]
+pub struct AuthResultTarget {
+    pub comm_method: Tag,
+    pub attr_url: String,
+}
+
+// Authentication session for which the communication method is chosen afterwards
+#[derive(Debug, Clone)]
+pub struct AuthFirst {
+    pub purpose: String,
+    pub auth_result: Option<String>,
+    pub context: Option<RequestContext>,
+}
+
+// Continuation and attribute url of a started session, to start another authentication method
+// with when authentication failed
+#[derive(Debug, Clone)]
+pub struct AuthRetry {
+    pub continuation: String,
+    pub attr_url: Option<String>,
+}
+
+// Authentication steps still to follow in a session, delivering their results to attr_url
+// and ending at continuation
+#[derive(Debug, Clone)]
+pub struct AuthChain {
+    pub steps: Vec<AuthStep>,
+    // Number of steps started before, binding each next_auth url to a single step
+    pub position: usize,
+    pub continuation: String,
+    pub attr_url: String,
+}
+
+#[derive(Debug)]
+struct Session {
+    created: Instant,
+    events: Vec<SessionEvent>,
+    sender: broadcast::Sender<(usize, SessionEvent)>,
+    auth_result_target: Option<AuthResultTarget>,
+    auth_first: Option<AuthFirst>,
+    auth_chain: Option<AuthChain>,
+    auth_retry: Option<AuthRetry>,
+    return_url: Option<String>,
+    // Client url of the comm method, visited on the way back to the return url
+    comm_client_url: Option<String>,
+    purpose: Option<String>,
+    comm_method: Option<String>,
+    state: Option<String>,
+    language: Option<String>,
+    // Nonces of the single-use step tokens already redeemed for the session
+    redeemed: HashSet<String>,
+}
+
+type Subscription = (
+    Vec<(usize, SessionEvent)>,
+    broadcast::Receiver<(usize, SessionEvent)>,
+);
+
+// Limits on the number of signed starts of a requestor, per minute and per (UTC) day
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct Quota {
+    per_minute: Option<u64>,
+    per_day: Option<u64>,
+}
+
+fn default_cap_window() -> u64 {
+    24 * 60 * 60
+}
+
+// Limit on the sessions of a purpose per window of time, a (UTC) day by default
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SessionCap {
+    max_sessions: u64,
+    // Length of the window in seconds
+    #[serde(default = "default_cap_window")]
+    window: u64,
+}
+
+// Signed starts of a requestor in the current minute and day, and since core was started
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RequestorUsage {
+    #[serde(skip)]
+    minute: u64,
+    #[serde(skip)]
+    day: u64,
+    this_minute: u64,
+    today: u64,
+    total: u64,
+}
+
+impl RequestorUsage {
+    // Start new windows when the minute or day has passed
+    fn roll(&mut self, now: u64) {
+        if self.minute != now / 60 {
+            self.minute = now / 60;
+            self.this_minute = 0;
+        }
+        if self.day != now / (24 * 60 * 60) {
+            self.day = now / (24 * 60 * 60);
+            self.today = 0;
+        }
+    }
+}
+
+// Whether the last authentication attempt of a session failed, at its start or afterwards
+fn auth_failed(events: &[SessionEvent]) -> bool {
+    events
+        .iter()
+        .rev()
+        .find_map(|event| match event {
+            SessionEvent::StartFailed | SessionEvent::AuthFailed { .. } => Some(true),
+            SessionEvent::AuthRetried { success, .. } => Some(!success),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+// Attributes requested from a user for a purpose, recorded when the user proceeded past the
+// selection of methods, for accountability under the GDPR
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ConsentRecord {
+    pub session_id: String,
+    pub purpose: String,
+    pub attributes: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub alternative_attributes: Vec<Vec<String>>,
+    pub auth_method: Option<Tag>,
+    pub comm_method: Option<Tag>,
+    // Opaque state of the requestor, so records can be erased by the requestor's reference
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    // Unix time in seconds
+    pub time: u64,
+}
+
+#[derive(Debug, Default, FromForm)]
+pub struct ConsentQuery {
+    session_id: Option<String>,
+    purpose: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+}
+
+impl ConsentQuery {
+    pub fn matches(&self, record: &ConsentRecord) -> bool {
+        !matches!(&self.session_id, Some(id) if *id != record.session_id)
+            && !matches!(&self.purpose, Some(purpose) if *purpose != record.purpose)
+            && !matches!(self.since, Some(since) if record.time < since)
+            && !matches!(self.until, Some(until) if record.time >= until)
+    }
+}
+
+// Sessions to erase, by id or by the opaque state the requestor started them with
+#[derive(Debug, FromForm)]
+pub struct ErasureQuery {
+    session_id: Option<String>,
+    state: Option<String>,
+}
+
+// Number of records removed by an erasure
+#[derive(Debug, Default, Serialize, PartialEq)]
+pub struct Erasure {
+    pub sessions: usize,
+    pub consent_records: usize,
+    pub short_urls: usize,
+    pub tel_tokens: usize,
+    pub dead_letters: usize,
+}
+
+// Event of any session, as forwarded to an event publisher
+#[derive(Debug, Clone)]
+pub struct LifecycleEvent {
+    pub session_id: String,
+    pub event_id: usize,
+    pub purpose: Option<String>,
+    pub event: SessionEvent,
+}
+
+// Sessions, requestor usage and consent records, shared between clones
+#[derive(Debug, Default, Clone)]
+pub struct SessionStore {
+    sessions: Arc<Mutex<HashMap<String, Session>>>,
+    usage: Arc<Mutex<HashMap<String, RequestorUsage>>>,
+    // Sessions of capped purposes in their current window, by purpose
+    purpose_starts: Arc<Mutex<HashMap<String, (u64, u64)>>>,
+    consents: Arc<Mutex<Vec<ConsentRecord>>>,
+    // Unix time until which nonces of signed start requests are taken, by issuer and nonce
+    request_nonces: Arc<Mutex<HashMap<(String, String), u64>>>,
+    // Unix time until which nonces of selection page tokens are taken
+    selection_nonces: Arc<Mutex<HashMap<String, u64>>>,
+    lifecycle: Arc<Mutex<Option<Sender<LifecycleEvent>>>>,
+    ids: Ids,
+}
+
+impl SessionStore {
+    pub fn with_ids(ids: Ids) -> Self {
+        SessionStore {
+            ids,
+            ..Default::default()
+        }
+    }
+
+    pub fn create(&self) -> String {
+        let id = self.ids.generate(IdKind::Session);
+
+        let (sender, _) = broadcast::channel(16);
+        self.sessions.lock().unwrap().insert(
+            id.clone(),
+            Session {
+                created: Instant::now(),
+                events: vec![],
+                sender,
+                auth_result_target: None,
+                auth_first: None,
+                auth_chain: None,
+                auth_retry: None,
+                return_url: None,
+                comm_client_url: None,
+                purpose: None,
+                comm_method: None,
+                state: None,
+                language: None,
+                redeemed: HashSet::new(),
+            },
+        );
+        id
+    }
+
+    // Mark a step token nonce as used, false if the session is unknown or it was used before
+    pub fn redeem_nonce(&self, id: &str, nonce: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(id) {
+            Some(session) => session.redeemed.insert(nonce.to_string()),
+            None => false,
+        }
+    }
+
+    // Mark the nonce of a signed start request as used until the request expires, false if it
+    // was used before
+    pub fn redeem_request_nonce(&self, issuer: &str, nonce: &str, expires: u64) -> bool {
+        let mut nonces = self.request_nonces.lock().unwrap();
+        let key = (issuer.to_string(), nonce.to_string());
+        if matches!(nonces.get(&key), Some(taken) if *taken >= unix_time()) {
+            return false;
+        }
+        nonces.insert(key, expires);
+        true
+    }
+
+    // Mark the nonce of a selection token as used until the token expires, false if it was used
+    // before
+    pub fn redeem_selection_nonce(&self, nonce: &str, expires: u64) -> bool {
+        let mut nonces = self.selection_nonces.lock().unwrap();
+        if matches!(nonces.get(nonce), Some(taken) if *taken >= unix_time()) {
+            return false;
+        }
+        nonces.insert(nonce.to_string(), expires);
+        true
+    }
+
+    // Remove sessions older than ttl, returning how many were removed. Nonces of expired
+    // requests are forgotten as well, as those requests are refused anyway.
+    pub fn purge_expired(&self, ttl: Duration) -> usize {
+        let now = unix_time();
+        self.request_nonces
+            .lock()
+            .unwrap()
+            .retain(|_, expires| *expires >= now);
+        self.selection_nonces
+            .lock()
+            .unwrap()
+            .retain(|_, expires| *expires >= now);
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|_, session| session.created.elapsed() < ttl);
+        before - sessions.len()
+    }
+
+    // Number of sessions and the age of the oldest
+    pub fn session_stats(&self) -> (usize, Option<Duration>) {
+        let sessions = self.sessions.lock().unwrap();
+        let oldest = sessions
+            .values()
+            .map(|session| session.created.elapsed())
+            .max();
+        (sessions.len(), oldest)
+    }
+
+    pub fn publish(&self, id: &str, event: SessionEvent) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            let event_id = session.events.len();
+            session.events.push(event.clone());
+            if let Some(lifecycle) = &*self.lifecycle.lock().unwrap() {
+                // Sessions don't wait for a slow broker, its events are dropped instead
+                let sent = lifecycle.try_send(LifecycleEvent {
+                    session_id: id.to_string(),
+                    event_id,
+                    purpose: session.purpose.clone(),
+                    event: event.clone(),
+                });
+                if let Err(TrySendError::Full(event)) = sent {
+                    metrics::record_event_dropped(event.purpose.as_deref().unwrap_or_default());
+                }
+            }
+            // Not having any listeners is fine, events are replayed on subscription
+            let _ = session.sender.send((event_id, event));
+        }
+    }
+
+    // Pass the events of all sessions on to a publisher as well
+    pub fn forward_events(&self, sender: Sender<LifecycleEvent>) {
+        *self.lifecycle.lock().unwrap() = Some(sender);
+    }
+
+    pub fn await_auth_result(&self, id: &str, target: AuthResultTarget) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.auth_result_target = Some(target);
+        }
+    }
+
+    pub fn take_auth_result_target(&self, id: &str) -> Option<AuthResultTarget> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.get_mut(id)?.auth_result_target.take()
+    }
+
+    pub fn set_auth_first(&self, id: &str, auth_first: AuthFirst) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.auth_first = Some(auth_first);
+        }
+    }
+
+    pub fn set_auth_chain(&self, id: &str, auth_chain: AuthChain) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.auth_chain = Some(auth_chain);
+        }
+    }
+
+    pub fn take_auth_chain(&self, id: &str) -> Option<AuthChain> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.get_mut(id)?.auth_chain.take()
+    }
+
+    pub fn set_auth_retry(&self, id: &str, auth_retry: AuthRetry) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.auth_retry = Some(auth_retry);
+        }
+    }
+
+    // How to start another authentication method, only while the last one failed
+    pub fn auth_retry(&self, id: &str) -> Option<AuthRetry> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)?;
+        if !auth_failed(&session.events) {
+            return None;
+        }
+        session.auth_retry.clone()
+    }
+
+    pub fn set_return_url(&self, id: &str, return_url: String) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.return_url = Some(return_url);
+        }
+    }
+
+    pub fn set_comm_client_url(&self, id: &str, client_url: String) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.comm_client_url = Some(client_url);
+        }
+    }
+
+    pub fn comm_client_url(&self, id: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id)?.comm_client_url.clone()
+    }
+
+    pub fn set_purpose(&self, id: &str, purpose: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.purpose = Some(purpose.to_string());
+        }
+    }
+
+    pub fn purpose(&self, id: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id)?.purpose.clone()
+    }
+
+    pub fn set_comm_method(&self, id: &str, comm_method: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.comm_method = Some(comm_method.to_string());
+        }
+    }
+
+    pub fn comm_method(&self, id: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id)?.comm_method.clone()
+    }
+
+    pub fn set_state(&self, id: &str, state: Option<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.state = state;
+        }
+    }
+
+    pub fn set_language(&self, id: &str, language: Option<String>) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get_mut(id) {
+            session.language = language;
+        }
+    }
+
+    // Language of the user, passed to every plugin started for the session
+    pub fn language(&self, id: &str) -> Option<String> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(id)?.language.clone()
+    }
+
+    // Take the url of the requestor app to return to, with whether the session started without
+    // authentication failing and the state of the requestor
+    pub fn take_return_url(&self, id: &str) -> Option<(String, bool, Option<String>)> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions.get_mut(id)?;
+        let started = session.events.iter().any(|event| {
+            matches!(
+                event,
+                SessionEvent::Started { .. } | SessionEvent::AuthRetried { success: true, .. }
+            )
+        }) && !auth_failed(&session.events);
+        Some((session.return_url.take()?, started, session.state.clone()))
+    }
+
+    // Keep the results of an auth-first session until the communication method is chosen
+    fn store_auth_result(&self, id: &str, auth_result: &str) -> bool {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get_mut(id).and_then(|s| s.auth_first.as_mut()) {
+            Some(auth_first) if auth_first.auth_result.is_none() => {
+                auth_first.auth_result = Some(auth_result.to_string());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Purpose of an auth-first session awaiting the choice of communication method, with whether
+    // its authentication result has arrived
+    pub fn auth_first_status(&self, id: &str) -> Option<(String, bool)> {
+        let sessions = self.sessions.lock().unwrap();
+        let auth_first = sessions.get(id)?.auth_first.as_ref()?;
+        Some((auth_first.purpose.clone(), auth_first.auth_result.is_some()))
+    }
+
+    // Take the purpose, authentication result and requestor context of an auth-first session,
+    // once authenticated
+    pub fn take_auth_first_result(
+        &self,
+        id: &str,
+    ) -> Result<(String, String, Option<RequestContext>), Error> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .get_mut(id)
+            .ok_or_else(|| Error::NoSuchSession(id.to_string()))?;
+        match session.auth_first.take() {
+            Some(AuthFirst {
+                purpose,
+                auth_result: Some(auth_result),
+                context,
+            }) => Ok((purpose, auth_result, context)),
+            auth_first => {
+                session.auth_first = auth_first;
+                Err(Error::BadRequest)
+            }
+        }
+    }
+
+    // Count a signed start of a requestor, refusing it when that would exceed its quota
+    pub fn count_start(&self, requestor: &str, quota: Option<&Quota>) -> Result<(), Error> {
+        let now = unix_time();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(requestor.to_string()).or_default();
+        usage.roll(now);
+
+        if let Some(quota) = quota {
+            if let Some(limit) = quota.per_minute.filter(|limit| usage.this_minute >= *limit) {
+                return Err(Error::QuotaExceeded {
+                    limit,
+                    retry_after: 60 - now % 60,
+                });
+            }
+            if let Some(limit) = quota.per_day.filter(|limit| usage.today >= *limit) {
+                return Err(Error::QuotaExceeded {
+                    limit,
+                    retry_after: 24 * 60 * 60 - now % (24 * 60 * 60),
+                });
+            }
+        }
+
+        usage.this_minute += 1;
+        usage.today += 1;
+        usage.total += 1;
+        Ok(())
+    }
+
+    // Count a session start of a purpose, refusing it when the purpose reached its cap
+    pub fn count_purpose_start(
+        &self,
+        purpose: &str,
+        cap: Option<&SessionCap>,
+    ) -> Result<(), Error> {
+        let cap = match cap {
+            Some(cap) => cap,
+            None => return Ok(()),
+        };
+        let now = unix_time();
+        let window = cap.window.max(1);
+        let mut starts = self.purpose_starts.lock().unwrap();
+        let (current, count) = starts.entry(purpose.to_string()).or_default();
+        if *current != now / window {
+            *current = now / window;
+            *count = 0;
+        }
+
+        if *count >= cap.max_sessions {
+            metrics::record_capacity_exceeded(purpose);
+            return Err(Error::CapacityExceeded {
+                purpose: purpose.to_string(),
+                retry_after: window - now % window,
+            });
+        }
+        *count += 1;
+        Ok(())
+    }
+
+    pub fn record_consent(&self, record: ConsentRecord) {
+        self.consents.lock().unwrap().push(record);
+    }
+
+    // Ids of the sessions started with a state, including those only left in consent records
+    pub fn sessions_with_state(&self, state: &str) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .sessions
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, session)| session.state.as_deref() == Some(state))
+            .map(|(id, _)| id.clone())
+            .collect();
+        for record in self.consents.lock().unwrap().iter() {
+            if record.state.as_deref() == Some(state) && !ids.contains(&record.session_id) {
+                ids.push(record.session_id.clone());
+            }
+        }
+        ids
+    }
+
+    // Remove a session, including any authentication results awaiting delivery, and its consent
+    // records. Returns whether the session existed and the number of removed consent records.
+    pub fn erase(&self, id: &str) -> (bool, usize) {
+        let removed = self.sessions.lock().unwrap().remove(id).is_some();
+        let mut consents = self.consents.lock().unwrap();
+        let before = consents.len();
+        consents.retain(|record| record.session_id != id);
+        (removed, before - consents.len())
+    }
+
+    // Remove consent records older than retention, returning how many were removed
+    pub fn purge_consents(&self, retention: Duration) -> usize {
+        let cutoff = unix_time().saturating_sub(retention.as_secs());
+        let mut consents = self.consents.lock().unwrap();
+        let before = consents.len();
+        consents.retain(|record| record.time >= cutoff);
+        before - consents.len()
+    }
+
+    pub fn consents(&self, query: &ConsentQuery) -> Vec<ConsentRecord> {
+        self.consents
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| query.matches(record))
+            .cloned()
+            .collect()
+    }
+
+    pub fn usage(&self) -> HashMap<String, RequestorUsage> {
+        let now = unix_time();
+        let mut usage = self.usage.lock().unwrap();
+        for requestor_usage in usage.values_mut() {
+            requestor_usage.roll(now);
+        }
+        usage.clone()
+    }
+
+    // Subscribe to events of a session, returning all events after last_event_id
+    // and a receiver for the events still to come
+    fn subscribe(&self, id: &str, last_event_id: Option<usize>) -> Option<Subscription> {
+        let sessions = self.sessions.lock().unwrap();
+        let session = sessions.get(id)?;
+        let first = last_event_id.map(|id| id + 1).unwrap_or(0);
+        Some((
+            session
+                .events
+                .iter()
+                .cloned()
+                .enumerate()
+                .skip(first)
+                .collect(),
+            session.sender.subscribe(),
+        ))
+    }
+}
+
+// Periodically purges expired sessions, short urls and tel tokens of all tenants, and consent
+// and audit records past their retention, until core shuts down
+pub struct CleanupFairing;
+
+#[rocket::async_trait]
+impl Fairing for CleanupFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Session cleanup",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(config) = rocket.state::<CoreConfig>() {
+            for config in config.all_tenants() {
+                let sessions = config.sessions().clone();
+                let short_urls = config.short_urls().cloned();
+                let tel_tokens = config.tel_tokens().cloned();
+                let retention = config.retention().clone();
+                let audit = config.audit_log();
+                let dead_letters = config.dead_letters().cloned();
+                let interval = config.cleanup_interval();
+                let shutdown = rocket.shutdown();
+                rocket::tokio::spawn(async move {
+                    loop {
+                        rocket::tokio::select! {
+                            _ = shutdown.clone() => break,
+                            _ = rocket::tokio::time::sleep(interval) => {}
+                        }
+                        let purged_sessions = sessions.purge_expired(retention.sessions());
+                        let purged_urls = short_urls
+                            .as_ref()
+                            .map(|short_urls| short_urls.purge_expired())
+                            .unwrap_or(0);
+                        let purged_tokens = tel_tokens
+                            .as_ref()
+                            .map(|tel_tokens| tel_tokens.purge_expired())
+                            .unwrap_or(0);
+                        if purged_sessions > 0 || purged_urls > 0 || purged_tokens > 0 {
+                            log::info!(
+                                "Purged {} expired sessions, {} expired short urls and {} expired tel tokens",
+                                purged_sessions,
+                                purged_urls,
+                                purged_tokens
+                            );
+                        }
+
+                        if let Some(retention) = retention.consents() {
+                            let cutoff = unix_time().saturating_sub(retention.as_secs());
+                            let purged = match &audit {
+                                Some(audit) if audit.is_readable() => {
+                                    audit.erase_consents(|record| record.time < cutoff).await
+                                }
+                                _ => Ok(sessions.purge_consents(retention)),
+                            };
+                            match purged {
+                                Ok(0) => {}
+                                Ok(purged) => {
+                                    log::info!("Purged {} consent records past retention", purged)
+                                }
+                                Err(e) => log::error!("Could not purge consent records: {}", e),
+                            }
+                        }
+                        if let (Some(audit), Some(retention)) = (&audit, retention.audit()) {
+                            match audit.purge(retention).await {
+                                Ok(0) => {}
+                                Ok(purged) => {
+                                    log::info!("Purged {} audit records past retention", purged)
+                                }
+                                Err(e) => log::error!("Could not purge audit records: {}", e),
+                            }
+                        }
+                        if let (Some(dead_letters), Some(retention)) =
+                            (&dead_letters, retention.dead_letters())
+                        {
+                            match dead_letters.purge_expired(retention).await {
+                                Ok(0) => {}
+                                Ok(purged) => {
+                                    log::info!("Purged {} dead letters past retention", purged)
+                                }
+                                Err(e) => log::error!("Could not purge dead letters: {}", e),
+                            }
+                        }
+                    }
+                });
+            }
+        }
+    }
+}
+
+pub struct LastEventId(Option<usize>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(
+            request
+                .headers()
+                .get_one("Last-Event-ID")
+                .and_then(|id| id.parse().ok()),
+        ))
+    }
+}
+
+#[get("/session/<id>/events")]
+pub fn session_events(
+    id: String,
+    last_event_id: LastEventId,
+    config: &CoreConfig,
+) -> Option<EventStream![]> {
+    let (backlog, mut receiver) = config.sessions().subscribe(&id, last_event_id.0)?;
+
+    Some(
+        EventStream! {
+            for (event_id, event) in backlog {
+                yield Event::json(&event).id(event_id.to_string());
+            }
+            loop {
+                match receiver.recv().await {
+                    Ok((event_id, event)) => {
+                        yield Event::json(&event).id(event_id.to_string());
+                    }
+                    // When lagging, let the client reconnect so it gets the missed events replayed
+                    Err(RecvError::Lagged(_)) | Err(RecvError::Closed) => break,
+                }
+            }
+        }
+        .heartbeat(Duration::from_secs(15)),
+    )
+}
+
+// Authentication result of a session, posted to the url core handed out with a token for it
+#[post(
+    "/session/<id>/auth_result/<token>",
+    format = "application/jwt",
+    data = "<auth_result>"
+)]
+pub async fn session_auth_result(
+    id: String,
+    token: String,
+    auth_result: String,
+    config: &CoreConfig,
+) -> Result<(), Error> {
+    config.verify_step_token(&id, "auth_result", &token)?;
+
+    // The result is encrypted for the comm plugin, so all we can check is that it is a jwt
+    jwt::decode_header(&auth_result).map_err(|_| Error::BadRequest)?;
+
+    // Auth-first sessions keep the result until a communication method is chosen
+    if config.sessions().store_auth_result(&id, &auth_result) {
+        config
+            .sessions()
+            .publish(&id, SessionEvent::AuthResultReceived);
+        return Ok(());
+    }
+
+    let target = config
+        .sessions()
+        .take_auth_result_target(&id)
+        .ok_or_else(|| Error::NoSuchSession(id.clone()))?;
+    let comm_method = config
+        .find_comm_method(&target.comm_method)
+        .ok_or_else(|| Error::NoSuchMethod(target.comm_method.to_string()))?;
+
+    let purpose = config.sessions().purpose(&id).unwrap_or_default();
+    let auth_result = comm_method.envelope(&auth_result, &purpose, &id, config.ui_signer())?;
+    let delivery = comm_method
+        .deliver_auth_result(&target.attr_url, &auth_result, &purpose)
+        .await;
+    config.sessions().publish(
+        &id,
+        SessionEvent::AuthResultDelivered {
+            success: delivery.is_ok(),
+        },
+    );
+    if delivery.is_err() {
+        // Allow the requestor to retry
+        config.sessions().await_auth_result(&id, target);
+    }
+
+    Ok(delivery?)
+}
+
+// Usage of signed starts by requestor, for billing and reporting
+#[get("/internal/usage")]
+pub fn requestor_usage(
+    _admin: AdminToken,
+    config: &CoreConfig,
+) -> Result<Json<HashMap<String, RequestorUsage>>, Status> {
+    Ok(Json(config.sessions().usage()))
+}
+
+fn consents_failed(e: std::io::Error) -> Status {
+    log::error!("Could not access consent records in the audit log: {}", e);
+    Status::InternalServerError
+}
+
+// Delete all state of a session, or of all sessions started with a requestor's state, for
+// right-to-erasure requests
+#[delete("/internal/sessions?<query..>")]
+pub async fn erase_sessions(
+    query: ErasureQuery,
+    _admin: AdminToken,
+    config: &CoreConfig,
+) -> Result<Json<Erasure>, Status> {
+    let ids = match (query.session_id, query.state) {
+        (Some(id), None) => vec![id],
+        (None, Some(state)) => {
+            let mut ids = config.sessions().sessions_with_state(&state);
+            if let Some(audit) = config.audit_log() {
+                for record in audit.consents().await.map_err(consents_failed)? {
+                    if record.state.as_deref() == Some(&state) && !ids.contains(&record.session_id)
+                    {
+                        ids.push(record.session_id);
+                    }
+                }
+            }
+            ids
+        }
+        _ => return Err(Status::BadRequest),
+    };
+
+    let mut erasure = Erasure::default();
+    for id in &ids {
+        let (removed, consent_records) = config.sessions().erase(id);
+        erasure.sessions += removed as usize;
+        erasure.consent_records += consent_records;
+        if let Some(audit) = config.audit_log() {
+            erasure.consent_records += audit
+                .erase_consents(|record| record.session_id == *id)
+                .await
+                .map_err(consents_failed)?;
+        }
+        if let Some(short_urls) = config.short_urls() {
+            erasure.short_urls += short_urls.erase_session(id);
+        }
+        if let Some(tel_tokens) = config.tel_tokens() {
+            erasure.tel_tokens += tel_tokens.erase_session(id);
+        }
+        if let Some(dead_letters) = config.dead_letters() {
+            erasure.dead_letters += dead_letters.erase_session(id).await.map_err(|e| {
+                log::error!("Could not erase dead letters of session {}: {}", id, e);
+                Status::InternalServerError
+            })?;
+        }
+    }
+    config
+        .audit(AuditEvent::Erasure {
+            sessions: erasure.sessions,
+            consent_records: erasure.consent_records,
+        })
+        .await;
+    Ok(Json(erasure))
+}
+
+// Attributes requested in started sessions, optionally filtered by session, purpose or a range of
+// unix times
+#[get("/internal/consents?<query..>")]
+pub async fn consent_records(
+    query: ConsentQuery,
+    _admin: AdminToken,
+    config: &CoreConfig,
+) -> Result<Json<Vec<ConsentRecord>>, Status> {
+    config
+        .consents(&query)
+        .await
+        .map(Json)
+        .map_err(consents_failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::figment::{providers::Serialized, Figment};
+    use serde_json::json;
+
+    use std::{convert::TryFrom, time::Instant};
+
+    use super::{
+        ConsentQuery, ConsentRecord, Quota, SessionCap, SessionEvent, SessionStore, SESSION_TTL,
+    };
+    use crate::{error::Error, methods::Tag};
+
+    #[test]
+    fn test_replay() {
+        let store = SessionStore::default();
+        let id = store.create();
+        store.publish(
+            &id,
+            SessionEvent::Started {
+                purpose: "test".into(),
+                auth_method: Tag::try_from("irma").ok(),
+                comm_method: None,
+                state: None,
+            },
+        );
+
+        let (backlog, mut receiver) = store.subscribe(&id, None).unwrap();
+        assert_eq!(backlog.len(), 1);
+        assert_eq!(backlog[0].0, 0);
+
+        store.publish(&id, SessionEvent::AuthResultDelivered { success: true });
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            (1, SessionEvent::AuthResultDelivered { success: true })
+        );
+
+        // Reconnecting replays only the events after the last seen one
+        let (backlog, _) = store.subscribe(&id, Some(0)).unwrap();
+        assert_eq!(
+            backlog,
+            vec![(1, SessionEvent::AuthResultDelivered { success: true })]
+        );
+        let (backlog, _) = store.subscribe(&id, Some(1)).unwrap();
+        assert!(backlog.is_empty());
+
+        assert!(store.subscribe("unknown", None).is_none());
+    }
+
+    #[test]
+    fn test_forward_events() {
+        let store = SessionStore::default();
+        let id = store.create();
+        let (sender, mut receiver) = rocket::tokio::sync::mpsc::channel(1);
+        store.forward_events(sender);
+
+        // Events beyond the backlog of the publisher are dropped rather than waited for
+        store.publish(&id, SessionEvent::AuthResultDelivered { success: true });
+        store.publish(&id, SessionEvent::AuthResultDelivered { success: false });
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.event_id, 0);
+        assert!(receiver.try_recv().is_err());
+        let (backlog, _) = store.subscribe(&id, None).unwrap();
+        assert_eq!(backlog.len(), 2);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let store = SessionStore::default();
+        let expired = store.create();
+        let active = store.create();
+        if let Some(created) = Instant::now().checked_sub(SESSION_TTL) {
+            store
+                .sessions
+                .lock()
+                .unwrap()
+                .get_mut(&expired)
+                .unwrap()
+                .created = created;
+            // Clones share their sessions
+            assert_eq!(store.clone().purge_expired(SESSION_TTL), 1);
+            assert!(store.subscribe(&expired, None).is_none());
+        }
+        assert!(store.subscribe(&active, None).is_some());
+        assert_eq!(store.purge_expired(SESSION_TTL), 0);
+    }
+
+    #[test]
+    fn test_quota() {
+        let store = SessionStore::default();
+        let quota = Figment::from(Serialized::defaults(json!({"per_minute": 2})))
+            .extract::<Quota>()
+            .unwrap();
+
+        assert!(store.count_start("test", Some(&quota)).is_ok());
+        assert!(store.count_start("test", Some(&quota)).is_ok());
+        assert!(matches!(
+            store.count_start("test", Some(&quota)),
+            Err(Error::QuotaExceeded { limit: 2, .. })
+        ));
+        // Requestors without quota are only counted
+        assert!(store.count_start("other", None).is_ok());
+
+        let usage = store.usage();
+        assert_eq!(usage["test"].total, 2);
+        assert_eq!(usage["other"].today, 1);
+    }
+
+    #[test]
+    fn test_request_nonces() {
+        let store = SessionStore::default();
+        let expires = super::unix_time() + 60;
+        assert!(store.redeem_request_nonce("municipality", "n0nc3", expires));
+        assert!(!store.redeem_request_nonce("municipality", "n0nc3", expires));
+        // Nonces are scoped to their issuer
+        assert!(store.redeem_request_nonce("other", "n0nc3", expires));
+
+        // Nonces of expired requests are forgotten
+        assert!(store.redeem_request_nonce("municipality", "expired", 0));
+        store.purge_expired(SESSION_TTL);
+        assert_eq!(store.request_nonces.lock().unwrap().len(), 2);
+        assert!(!store.redeem_request_nonce("municipality", "n0nc3", expires));
+    }
+
+    #[test]
+    fn test_session_cap() {
+        let store = SessionStore::default();
+        let cap = Figment::from(Serialized::defaults(json!({"max_sessions": 2})))
+            .extract::<SessionCap>()
+            .unwrap();
+        assert_eq!(cap.window, 24 * 60 * 60);
+
+        assert!(store.count_purpose_start("test", Some(&cap)).is_ok());
+        assert!(store.count_purpose_start("test", Some(&cap)).is_ok());
+        match store.count_purpose_start("test", Some(&cap)) {
+            Err(Error::CapacityExceeded {
+                purpose,
+                retry_after,
+            }) => {
+                assert_eq!(purpose, "test");
+                assert!(retry_after <= cap.window);
+            }
+            result => panic!("Unexpected result {:?}", result),
+        }
+        // Purposes are capped separately
+        assert!(store.count_purpose_start("other", Some(&cap)).is_ok());
+        assert!(store.count_purpose_start("uncapped", None).is_ok());
+    }
+
+    #[test]
+    fn test_erase() {
+        let store = SessionStore::default();
+        let first = store.create();
+        let second = store.create();
+        let other = store.create();
+        store.set_state(&first, Some("reference".into()));
+        store.set_state(&second, Some("reference".into()));
+        store.record_consent(ConsentRecord {
+            session_id: first.clone(),
+            purpose: "report_move".into(),
+            attributes: vec!["email".into()],
+            alternative_attributes: vec![],
+            auth_method: None,
+            comm_method: Tag::try_from("call").ok(),
+            state: Some("reference".into()),
+            time: 100,
+        });
+        // Sessions are found through their consent records after being purged
+        store.sessions.lock().unwrap().remove(&first);
+
+        let mut ids = store.sessions_with_state("reference");
+        ids.sort();
+        let mut expected = vec![first.clone(), second.clone()];
+        expected.sort();
+        assert_eq!(ids, expected);
+
+        assert_eq!(store.erase(&first), (false, 1));
+        assert_eq!(store.erase(&second), (true, 0));
+        assert!(store.sessions_with_state("reference").is_empty());
+        assert!(store.subscribe(&other, None).is_some());
+    }
+
+    #[test]
+    fn test_consents() {
+        let store = SessionStore::default();
+        let record = |session_id: &str, purpose: &str, time| ConsentRecord {
+            session_id: session_id.into(),
+            purpose: purpose.into(),
+            attributes: vec!["email".into()],
+            alternative_attributes: vec![],
+            auth_method: Tag::try_from("irma").ok(),
+            comm_method: None,
+            state: None,
+            time,
+        };
+        store.record_consent(record("a", "report_move", 100));
+        store.record_consent(record("b", "report_move", 200));
+        store.record_consent(record("c", "request_permit", 300));
+
+        let query = |session_id: Option<&str>, purpose: Option<&str>, since, until| ConsentQuery {
+            session_id: session_id.map(|id| id.into()),
+            purpose: purpose.map(|purpose| purpose.into()),
+            since,
+            until,
+        };
+        assert_eq!(store.consents(&query(None, None, None, None)).len(), 3);
+        assert_eq!(
+            store.consents(&query(Some("b"), None, None, None)),
+            vec![record("b", "report_move", 200)]
+        );
+        assert_eq!(
+            store.consents(&query(None, Some("report_move"), Some(150), None)),
+            vec![record("b", "report_move", 200)]
+        );
+        assert_eq!(
+            store
+                .consents(&query(None, None, Some(100), Some(300)))
+                .len(),
+            2
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{config::CoreConfig, idgen::IdKind};
+use rocket::response::Redirect;
+use serde::Deserialize;
+
+fn default_ttl() -> u64 {
+    300
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ShortUrlConfig {
+    // Validity of a short url in seconds
+    #[serde(default = "default_ttl")]
+    ttl: u64,
+}
+
+// Creation, url and session of a short url
+type ShortUrl = (Instant, String, String);
+
+// Single-use short urls redirecting to client urls, for systems only able to carry short tokens.
+// Clones share their urls.
+#[derive(Debug, Clone)]
+pub struct ShortUrlStore {
+    ttl: Duration,
+    urls: Arc<Mutex<HashMap<String, ShortUrl>>>,
+}
+
+impl From<ShortUrlConfig> for ShortUrlStore {
+    fn from(config: ShortUrlConfig) -> Self {
+        ShortUrlStore {
+            ttl: Duration::from_secs(config.ttl),
+            urls: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl ShortUrlStore {
+    pub fn shorten(&self, url: String, session_id: &str, config: &CoreConfig) -> String {
+        let token = config.ids().generate(IdKind::ShortUrl);
+
+        self.urls
+            .lock()
+            .unwrap()
+            .insert(token.clone(), (Instant::now(), url, session_id.to_string()));
+        format!("{}/c/{}", config.server_url(), token)
+    }
+
+    // Remove expired short urls, returning how many were removed
+    pub fn purge_expired(&self) -> usize {
+        let mut urls = self.urls.lock().unwrap();
+        let before = urls.len();
+        urls.retain(|_, (created, _, _)| created.elapsed() < self.ttl);
+        before - urls.len()
+    }
+
+    // Remove the short urls of a session, returning how many were removed
+    pub fn erase_session(&self, session_id: &str) -> usize {
+        let mut urls = self.urls.lock().unwrap();
+        let before = urls.len();
+        urls.retain(|_, (_, _, session)| session != session_id);
+        before - urls.len()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    fn take(&self, token: &str) -> Option<String> {
+        let (created, url, _) = self.urls.lock().unwrap().remove(token)?;
+        if created.elapsed() < self.ttl {
+            Some(url)
+        } else {
+            None
+        }
+    }
+}
+
+#[get("/c/<token>")]
+pub fn short_url(token: String, config: &CoreConfig) -> Option<Redirect> {
+    let url = config.short_urls()?.take(&token)?;
+    Some(Redirect::to(url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ShortUrlConfig, ShortUrlStore};
+
+    #[test]
+    fn test_take() {
+        let store = ShortUrlStore::from(ShortUrlConfig { ttl: 300 });
+        store.urls.lock().unwrap().insert(
+            "token".into(),
+            (
+                std::time::Instant::now(),
+                "https://example.com".into(),
+                "session".into(),
+            ),
+        );
+        assert_eq!(store.take("token"), Some("https://example.com".into()));
+        // Short urls are single use
+        assert_eq!(store.take("token"), None);
+
+        let store = ShortUrlStore::from(ShortUrlConfig { ttl: 0 });
+        store.urls.lock().unwrap().insert(
+            "token".into(),
+            (
+                std::time::Instant::now(),
+                "https://example.com".into(),
+                "session".into(),
+            ),
+        );
+        assert_eq!(store.take("token"), None);
+    }
+
+    #[test]
+    fn test_purge_expired() {
+        let store = ShortUrlStore::from(ShortUrlConfig { ttl: 0 });
+        store.urls.lock().unwrap().insert(
+            "token".into(),
+            (
+                std::time::Instant::now(),
+                "https://example.com".into(),
+                "session".into(),
+            ),
+        );
+        assert_eq!(store.clone().purge_expired(), 1);
+        assert!(store.urls.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+use std::future::Future;
+
+use crate::{error::Error, methods::PluginHeaders, vault::VaultConfig};
+use josekit::{
+    jwk::Jwk,
+    jws::{JwsAlgorithm, JwsSigner, ES256, RS256},
+    JoseError,
+};
+use rocket::tokio::runtime::{Builder, Handle, RuntimeFlavor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum SignatureAlgorithm {
+    RS256,
+    ES256,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum SigningBackendConfig {
+    // Vault transit engine, using the connection settings of the vault configuration
+    VaultTransit {
+        key: String,
+        #[serde(default = "default_transit_mount")]
+        mount: String,
+    },
+    // Signing service in front of a KMS or HSM
+    Http {
+        url: String,
+        #[serde(default)]
+        headers: PluginHeaders,
+    },
+}
+
+fn default_transit_mount() -> String {
+    "transit".into()
+}
+
+// Signing key kept outside of core, as alternative to ui_signing_privkey
+#[derive(Debug, Deserialize)]
+pub struct ExternalSignerConfig {
+    algorithm: SignatureAlgorithm,
+    key_id: Option<String>,
+    // Public key, which core can't derive itself, to publish at /.well-known/jwks.json
+    public_jwk: Option<Map<String, Value>>,
+    #[serde(flatten)]
+    backend: SigningBackendConfig,
+}
+
+#[derive(Debug, Clone)]
+enum SigningBackend {
+    VaultTransit {
+        key: String,
+        mount: String,
+        vault: VaultConfig,
+    },
+    Http {
+        url: String,
+        headers: PluginHeaders,
+    },
+}
+
+#[derive(Serialize)]
+struct SignRequest {
+    input: String,
+}
+
+#[derive(Deserialize)]
+struct SignResponse {
+    signature: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct ExternalSigner {
+    algorithm: SignatureAlgorithm,
+    key_id: Option<String>,
+    public_jwk: Option<Jwk>,
+    backend: SigningBackend,
+}
+
+impl ExternalSigner {
+    pub fn new(config: ExternalSignerConfig, vault: Option<VaultConfig>) -> Result<Self, String> {
+        let backend = match config.backend {
+            SigningBackendConfig::VaultTransit { key, mount } => SigningBackend::VaultTransit {
+                key,
+                mount,
+                vault: vault.ok_or("The vault transit signer requires a vault configuration")?,
+            },
+            SigningBackendConfig::Http { url, headers } => SigningBackend::Http { url, headers },
+        };
+        let public_jwk = config
+            .public_jwk
+            .map(Jwk::from_map)
+            .transpose()
+            .map_err(|e| format!("Invalid public_jwk: {}", e))?;
+        Ok(ExternalSigner {
+            algorithm: config.algorithm,
+            key_id: config.key_id,
+            public_jwk,
+            backend,
+        })
+    }
+
+    pub fn public_jwk(&self) -> Option<&Jwk> {
+        self.public_jwk.as_ref()
+    }
+
+    async fn sign_remote(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        match &self.backend {
+            SigningBackend::VaultTransit { key, mount, vault } => {
+                vault
+                    .transit_sign(mount, key, message, self.algorithm)
+                    .await
+            }
+            SigningBackend::Http { url, headers } => {
+                let signature = headers
+                    .client()?
+                    .post(url)
+                    .json(&SignRequest {
+                        input: base64::encode(message),
+                    })
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json::<SignResponse>()
+                    .await?
+                    .signature;
+                // Signatures are expected in JWS form, e.g. r || s for ECDSA
+                base64::decode(signature)
+                    .map_err(|e| Error::Jwt(JoseError::InvalidSignature(e.into())))
+            }
+        }
+    }
+}
+
+// Run a future to completion from synchronous code. On a multi-threaded runtime, such as the
+// one core runs on, blocking the current worker only moves its other tasks to another thread.
+// Blocking in place panics on other runtimes, so there the future gets a thread of its own.
+fn block_on<F, T>(future: F) -> Result<T, Error>
+where
+    F: Future<Output = Result<T, Error>> + Send + 'static,
+    T: Send + 'static,
+{
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            rocket::tokio::task::block_in_place(|| handle.block_on(future))
+        }
+        _ => std::thread::spawn(move || {
+            Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .map_err(|e| Error::Jwt(JoseError::InvalidSignature(e.into())))?
+                .block_on(future)
+        })
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic)),
+    }
+}
+
+impl JwsSigner for ExternalSigner {
+    fn algorithm(&self) -> &dyn JwsAlgorithm {
+        match self.algorithm {
+            SignatureAlgorithm::RS256 => &RS256,
+            SignatureAlgorithm::ES256 => &ES256,
+        }
+    }
+
+    fn key_id(&self) -> Option<&str> {
+        self.key_id.as_deref()
+    }
+
+    fn signature_len(&self) -> usize {
+        match self.algorithm {
+            // Assuming a 2048 bit key
+            SignatureAlgorithm::RS256 => 256,
+            SignatureAlgorithm::ES256 => 64,
+        }
+    }
+
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, JoseError> {
+        let signer = self.clone();
+        let message = message.to_vec();
+        block_on(async move { signer.sign_remote(&message).await }).map_err(|e| {
+            log::error!("Could not sign using external signer: {}", e);
+            JoseError::InvalidSignature(e.into())
+        })
+    }
+
+    fn box_clone(&self) -> Box<dyn JwsSigner> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpmock::MockServer;
+    use josekit::jws::JwsSigner;
+    use rocket::figment::{providers::Serialized, Figment};
+    use serde_json::json;
+
+    use super::{ExternalSigner, ExternalSignerConfig};
+
+    #[test]
+    fn test_http_signer() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/sign")
+                .method(httpmock::Method::POST)
+                .header("Authorization", "Bearer kms_token")
+                .json_body(json!({"input": "aGVhZGVyLnBheWxvYWQ="}));
+            then.status(200)
+                .json_body(json!({"signature": "c2lnbmF0dXJl"}));
+        });
+
+        let config = Figment::from(Serialized::defaults(json!({
+            "type": "http",
+            "algorithm": "ES256",
+            "key_id": "core-ui",
+            "url": server.url("/sign"),
+            "headers": {"Authorization": "Bearer kms_token"},
+        })))
+        .extract::<ExternalSignerConfig>()
+        .unwrap();
+        let signer = ExternalSigner::new(config, None).unwrap();
+        assert_eq!(signer.key_id(), Some("core-ui"));
+        assert_eq!(signer.algorithm().name(), "ES256");
+
+        let signature = tokio_test::block_on(signer.sign_remote(b"header.payload")).unwrap();
+        assert_eq!(signature, b"signature");
+
+        // Signing from synchronous code works outside a multi-threaded runtime too
+        assert_eq!(signer.sign(b"header.payload").unwrap(), b"signature");
+        let signature = tokio_test::block_on(async { signer.sign(b"header.payload") }).unwrap();
+        assert_eq!(signature, b"signature");
+    }
+
+    #[test]
+    fn test_transit_requires_vault() {
+        let config = Figment::from(Serialized::defaults(json!({
+            "type": "vault_transit",
+            "algorithm": "RS256",
+            "key": "core-ui",
+        })))
+        .extract::<ExternalSignerConfig>()
+        .unwrap();
+        assert!(ExternalSigner::new(config, None).is_err());
+    }
+}
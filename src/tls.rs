@@ -0,0 +1,215 @@
+// Trust settings for outbound TLS to plugins, for environments with their own PKI. Additional
+// roots are handed to reqwest, pinning needs a rustls verifier checking the chain first.
+use std::{sync::Arc, time::SystemTime};
+
+use reqwest::{Certificate, ClientBuilder};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    ClientConfig, RootCertStore, ServerName,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct PluginTlsConfig {
+    // PEM files with root certificates to trust next to the system roots
+    #[serde(default)]
+    ca_certificates: Vec<String>,
+    // Base64 SHA-256 hashes of public keys, one of which must occur in the presented chain
+    #[serde(default)]
+    spki_pins: Vec<String>,
+}
+
+// Apply the global settings and those of a method to a client. Methods add to the global roots,
+// and their pins replace the global ones.
+pub fn configure(
+    builder: ClientBuilder,
+    global: &PluginTlsConfig,
+    method: &PluginTlsConfig,
+) -> Result<ClientBuilder, String> {
+    let mut roots = vec![];
+    for path in global.ca_certificates.iter().chain(&method.ca_certificates) {
+        roots.extend(read_certificates(path)?);
+    }
+    let pins = if method.spki_pins.is_empty() {
+        &global.spki_pins
+    } else {
+        &method.spki_pins
+    };
+    let pins = pins
+        .iter()
+        .map(|pin| match base64::decode(pin) {
+            Ok(pin) if pin.len() == 32 => Ok(pin),
+            _ => Err(format!("Invalid SPKI pin {}", pin)),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if pins.is_empty() {
+        return roots.iter().try_fold(builder, |builder, der| {
+            let certificate = Certificate::from_der(der).map_err(|e| e.to_string())?;
+            Ok(builder.add_root_certificate(certificate))
+        });
+    }
+
+    let mut store = RootCertStore::empty();
+    let native = rustls_native_certs::load_native_certs()
+        .map_err(|e| format!("Could not load system root certificates: {}", e))?;
+    store.add_parsable_certificates(&native.into_iter().map(|c| c.0).collect::<Vec<_>>());
+    store.add_parsable_certificates(&roots);
+    let mut config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(PinnedVerifier {
+            inner: WebPkiVerifier::new(store, None),
+            pins,
+        }))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(builder.use_preconfigured_tls(config))
+}
+
+fn read_certificates(path: &str) -> Result<Vec<Vec<u8>>, String> {
+    let pem = std::fs::read(path)
+        .map_err(|e| format!("Could not read CA certificates {}: {}", path, e))?;
+    match rustls_pemfile::certs(&mut pem.as_slice()) {
+        Ok(certificates) if !certificates.is_empty() => Ok(certificates),
+        _ => Err(format!("No certificates found in {}", path)),
+    }
+}
+
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<Vec<u8>>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            scts,
+            ocsp_response,
+            now,
+        )?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|certificate| spki(&certificate.0))
+            .any(|spki| {
+                self.pins
+                    .iter()
+                    .any(|pin| pin[..] == Sha256::digest(spki)[..])
+            });
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General(
+                "No pinned public key in certificate chain".into(),
+            ))
+        }
+    }
+}
+
+// Split off the first DER element, returning its contents and the remainder
+fn der_element(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&length, rest) = input.get(1..)?.split_first()?;
+    let (length, rest) = if length < 0x80 {
+        (length as usize, rest)
+    } else {
+        let size = (length & 0x7f) as usize;
+        if size == 0 || size > std::mem::size_of::<usize>() || rest.len() < size {
+            return None;
+        }
+        let (length, rest) = rest.split_at(size);
+        (length.iter().fold(0, |acc, &b| acc << 8 | b as usize), rest)
+    };
+    if rest.len() < length {
+        return None;
+    }
+    Some(rest.split_at(length))
+}
+
+// The encoded SubjectPublicKeyInfo of a DER certificate
+fn spki(certificate: &[u8]) -> Option<&[u8]> {
+    let (certificate, _) = der_element(certificate)?;
+    let (mut tbs, _) = der_element(certificate)?;
+    // Skip the optional version, then serial, signature algorithm, issuer, validity and subject
+    if tbs.first() == Some(&0xa0) {
+        tbs = der_element(tbs)?.1;
+    }
+    for _ in 0..5 {
+        tbs = der_element(tbs)?.1;
+    }
+    let (_, rest) = der_element(tbs)?;
+    Some(&tbs[..tbs.len() - rest.len()])
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::{configure, spki, PluginTlsConfig};
+
+    // Self-signed certificate for plugin.test
+    const CERTIFICATE: &'static str = "
+-----BEGIN CERTIFICATE-----
+MIIBhDCCASmgAwIBAgIURiqEi0LPnaSWUnRvIWu+lKj/800wCgYIKoZIzj0EAwIw
+FjEUMBIGA1UEAwwLcGx1Z2luLnRlc3QwIBcNMjYxMDE3MDUxNjUxWhgPMjEyNjA5
+MjMwNTE2NTFaMBYxFDASBgNVBAMMC3BsdWdpbi50ZXN0MFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAEb4oHPP7QbNuSxXPsXtl0oEQF4Zqf94rnNfGgzq/0vPYUFsfq
+qn79YykJ4SdtbUxmcw5TSi6FuFPYbCVjA1jX+6NTMFEwHQYDVR0OBBYEFAYvwsJs
+BkPoXu3gK++xYWkHOL5VMB8GA1UdIwQYMBaAFAYvwsJsBkPoXu3gK++xYWkHOL5V
+MA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSQAwRgIhALts+MFlnBU+h6bs
+AbHPXFwgTIngKK2yDOmPAfF4uVhNAiEAxS06DbYYu+Jpr4PoiIgCJf7vw5GI9cMD
+7mUvx9OC1q4=
+-----END CERTIFICATE-----
+";
+    const PIN: &'static str = "C7s6iTseiQUYl3Ro7sOer9N3hteWD0jSUMMaeQrn3bE=";
+
+    #[test]
+    fn test_spki() {
+        let der = rustls_pemfile::certs(&mut CERTIFICATE.as_bytes())
+            .unwrap()
+            .remove(0);
+        let key = spki(&der).unwrap();
+        assert_eq!(base64::encode(Sha256::digest(key)), PIN);
+
+        // Truncated certificates are rejected rather than read out of bounds
+        assert!((0..der.len()).all(|len| spki(&der[..len]).is_none()));
+    }
+
+    #[test]
+    fn test_configure() {
+        let path = std::env::temp_dir().join(format!("core-test-{}.pem", std::process::id()));
+        std::fs::write(&path, CERTIFICATE).unwrap();
+        let global = PluginTlsConfig {
+            ca_certificates: vec![path.to_str().unwrap().to_string()],
+            spki_pins: vec![],
+        };
+        let method = PluginTlsConfig {
+            ca_certificates: vec![],
+            spki_pins: vec![PIN.to_string()],
+        };
+        assert!(configure(reqwest::Client::builder(), &global, &Default::default()).is_ok());
+        assert!(configure(reqwest::Client::builder(), &global, &method).is_ok());
+
+        let invalid = PluginTlsConfig {
+            ca_certificates: vec![],
+            spki_pins: vec!["c2hvcnQ=".into()],
+        };
+        assert!(configure(reqwest::Client::builder(), &global, &invalid).is_err());
+        let missing = PluginTlsConfig {
+            ca_certificates: vec!["/nonexistent.pem".into()],
+            spki_pins: vec![],
+        };
+        assert!(configure(reqwest::Client::builder(), &missing, &Default::default()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
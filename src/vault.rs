@@ -0,0 +1,568 @@
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    time::Duration,
+};
+
+use crate::{
+    config::{CoreConfig, RequestorKeys, TokenSecret},
+    error::Error,
+    health::Health,
+    requestors::ManagedRequestors,
+    signer::SignatureAlgorithm,
+};
+use id_contact_jwt::SignKeyConfig;
+use josekit::{jws::JwsVerifier, JoseError};
+use rocket::{
+    fairing::{Fairing, Info, Kind},
+    Orbit, Rocket,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+fn default_mount() -> String {
+    "secret".into()
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum VaultAuth {
+    Token {
+        token: TokenSecret,
+    },
+    AppRole {
+        role_id: String,
+        secret_id: TokenSecret,
+    },
+}
+
+// Key material kept in a vault KV (version 2) secrets engine
+#[derive(Debug, Clone, Deserialize)]
+pub struct VaultConfig {
+    address: String,
+    #[serde(default = "default_mount")]
+    mount: String,
+    // Secret containing internal_secret and ui_signing_privkey
+    secret_path: Option<String>,
+    // Secret containing a key configuration per requestor
+    requestor_keys_path: Option<String>,
+    #[serde(flatten)]
+    auth: VaultAuth,
+    // Interval in seconds at which requestor keys are reloaded, to pick up rotated keys
+    refresh_interval: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct LoginAuth {
+    client_token: String,
+}
+
+#[derive(Deserialize)]
+struct LoginResponse {
+    auth: LoginAuth,
+}
+
+#[derive(Deserialize)]
+struct KvData {
+    data: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct KvResponse {
+    data: KvData,
+}
+
+#[derive(Deserialize)]
+struct TransitSignature {
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct TransitSignResponse {
+    data: TransitSignature,
+}
+
+impl VaultConfig {
+    async fn token(&self, client: &reqwest::Client) -> Result<String, Error> {
+        match &self.auth {
+            VaultAuth::Token { token } => Ok(token.0.clone()),
+            VaultAuth::AppRole { role_id, secret_id } => Ok(client
+                .post(format!("{}/v1/auth/approle/login", self.address))
+                .json(&serde_json::json!({
+                    "role_id": role_id,
+                    "secret_id": secret_id.0,
+                }))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<LoginResponse>()
+                .await?
+                .auth
+                .client_token),
+        }
+    }
+
+    async fn read(
+        &self,
+        client: &reqwest::Client,
+        token: &str,
+        path: &str,
+    ) -> Result<HashMap<String, Value>, Error> {
+        Ok(client
+            .get(format!("{}/v1/{}/data/{}", self.address, self.mount, path))
+            .header("X-Vault-Token", token)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<KvResponse>()
+            .await?
+            .data
+            .data)
+    }
+
+    async fn connect(&self) -> Result<(reqwest::Client, String), Error> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()?;
+        let token = self.token(&client).await?;
+        Ok((client, token))
+    }
+
+    // Fetch key material as configuration values, taking precedence over the configuration files
+    pub async fn secrets(&self) -> Result<HashMap<String, Value>, Error> {
+        let (client, token) = self.connect().await?;
+
+        let mut secrets = HashMap::new();
+        if let Some(path) = &self.secret_path {
+            let mut secret = self.read(&client, &token, path).await?;
+            for key in &["internal_secret", "ui_signing_privkey"] {
+                if let Some(value) = secret.remove(*key) {
+                    secrets.insert(key.to_string(), value);
+                }
+            }
+        }
+        if let Some(path) = &self.requestor_keys_path {
+            let keys = self.read(&client, &token, path).await?;
+            secrets.insert(
+                "authonly_request_keys".into(),
+                Value::Object(keys.into_iter().collect()),
+            );
+        }
+        Ok(secrets)
+    }
+
+    // Sign a message with a key of the transit engine, returning the signature in JWS form
+    pub async fn transit_sign(
+        &self,
+        mount: &str,
+        key: &str,
+        message: &[u8],
+        algorithm: SignatureAlgorithm,
+    ) -> Result<Vec<u8>, Error> {
+        let (client, token) = self.connect().await?;
+        let mut request = serde_json::json!({
+            "input": base64::encode(message),
+            "marshaling_algorithm": "jws",
+        });
+        if let SignatureAlgorithm::RS256 = algorithm {
+            request["signature_algorithm"] = "pkcs1v15".into();
+        }
+        let response = client
+            .post(format!(
+                "{}/v1/{}/sign/{}/sha2-256",
+                self.address, mount, key
+            ))
+            .header("X-Vault-Token", token)
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<TransitSignResponse>()
+            .await?;
+
+        // Signatures are prefixed with the key version, e.g. vault:v1:
+        let signature = response
+            .data
+            .signature
+            .rsplit(':')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        base64::decode_config(signature, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| Error::Jwt(JoseError::InvalidSignature(e.into())))
+    }
+
+    // Load the requestor keys in vault, removing those no longer there. Requestors of which the
+    // key fails to parse keep their last good key, so one bad key doesn't fail the others.
+    // Managed requestors keep theirs too, as the admin api owns those names.
+    async fn reload_requestor_keys(
+        &self,
+        path: &str,
+        keys: &RequestorKeys,
+        previous: &mut HashSet<String>,
+        managed: Option<&ManagedRequestors>,
+        health: &Health,
+    ) {
+        let current = match self.connect().await {
+            Ok((client, token)) => self.read(&client, &token, path).await,
+            Err(e) => Err(e),
+        };
+        let current = match current {
+            Ok(current) => current,
+            Err(e) => {
+                log::error!("Could not reload requestor keys from vault: {}", e);
+                health.degrade(
+                    "requestor_keys",
+                    "Could not reach vault, serving previously loaded keys".into(),
+                );
+                return;
+            }
+        };
+
+        let mut stale = vec![];
+        let mut shadowed = vec![];
+        // Managed requestors only change with this lock held, so they can't be added meanwhile
+        let mut keys = keys.write().unwrap();
+        let is_managed =
+            |requestor: &str| matches!(managed, Some(managed) if managed.contains(requestor));
+        for requestor in previous.drain() {
+            if !current.contains_key(&requestor) && !is_managed(&requestor) {
+                keys.remove(&requestor);
+            }
+        }
+        for (requestor, key) in current {
+            if is_managed(&requestor) {
+                log::error!("Ignoring vault key of managed requestor {}", requestor);
+                shadowed.push(requestor);
+                continue;
+            }
+            previous.insert(requestor.clone());
+            let key = serde_json::from_value::<SignKeyConfig>(key)
+                .ok()
+                .and_then(|key| Box::<dyn JwsVerifier>::try_from(key).ok());
+            match key {
+                Some(key) => {
+                    keys.insert(requestor, key);
+                }
+                None => {
+                    log::error!("Could not parse requestor key for requestor {}", requestor);
+                    stale.push(requestor);
+                }
+            }
+        }
+        stale.sort();
+        shadowed.sort();
+        let problems: Vec<String> = [
+            (
+                stale,
+                "Invalid keys in vault, serving previous keys for requestors",
+            ),
+            (shadowed, "Keys in vault ignored for managed requestors"),
+        ]
+        .iter()
+        .filter(|(requestors, _)| !requestors.is_empty())
+        .map(|(requestors, problem)| format!("{} {}", problem, requestors.join(", ")))
+        .collect();
+        if problems.is_empty() {
+            health.recover("requestor_keys");
+        } else {
+            health.degrade("requestor_keys", problems.join("; "));
+        }
+    }
+
+    // Keep requestor keys in sync with vault. Keys from the configuration files are left alone.
+    fn refresh_requestor_keys(
+        self,
+        keys: RequestorKeys,
+        managed: Option<ManagedRequestors>,
+        health: Health,
+    ) {
+        let (path, interval) = match (&self.requestor_keys_path, self.refresh_interval) {
+            (Some(path), Some(interval)) => (path.clone(), Duration::from_secs(interval)),
+            _ => return,
+        };
+
+        rocket::tokio::spawn(async move {
+            let mut previous = HashSet::new();
+            loop {
+                self.reload_requestor_keys(&path, &keys, &mut previous, managed.as_ref(), &health)
+                    .await;
+                rocket::tokio::time::sleep(interval).await;
+            }
+        });
+    }
+}
+
+pub struct VaultRefreshFairing;
+
+#[rocket::async_trait]
+impl Fairing for VaultRefreshFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Vault key refresh",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        if let Some(config) = rocket.state::<CoreConfig>() {
+            for config in config.all_tenants() {
+                if let Some(vault) = config.vault() {
+                    vault.clone().refresh_requestor_keys(
+                        config.requestor_keys(),
+                        config.managed_requestors().cloned(),
+                        config.health().clone(),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, convert::TryFrom};
+
+    use httpmock::MockServer;
+    use rocket::figment::{providers::Serialized, Figment};
+    use serde_json::json;
+
+    use super::VaultConfig;
+    use crate::{
+        config::RequestorKeys, health::Health, requestors::ManagedRequestors,
+        signer::SignatureAlgorithm,
+    };
+    use id_contact_jwt::SignKeyConfig;
+    use josekit::jws::JwsVerifier;
+
+    const REQUESTOR_KEY: &'static str = "-----BEGIN PUBLIC KEY-----
+MIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEA5/wRrT2T4GGvuQYcWjLr
+/lFe51sTV2FLd3GAaMiHN8Q/VT/XEhP/kZ6042l1Bj2VpZ2yMxv294JKwBCINc34
+8VLYd+DfkMnJ4yX9LZHK2Wke6tCWBB9mYgGjMwCNdXczbl96x1/HevaTorvk91rz
+Cvzw6vV08jtprAyN5aYMU4I0/cVJwi03bh/skraAB110mQSqi1QU/2z6Hkuf7+/x
+/bACxviWCyPCd/wkXNpFhTcRlfFeyKcy0pwFx1OLCDJ1qY7oU+z1wcypeOHeiUSx
+riSHlWaT24ke+J78GGVmnCZdu/MRuun5hvgaiWxnhIBmExJY6vRuMlwkbRqOft5Q
+TQIDAQAB
+-----END PUBLIC KEY-----";
+
+    #[test]
+    fn test_secrets_approle() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/v1/auth/approle/login")
+                .method(httpmock::Method::POST)
+                .json_body(json!({"role_id": "core", "secret_id": "very_secret"}));
+            then.status(200)
+                .json_body(json!({"auth": {"client_token": "vault_token"}}));
+        });
+        server.mock(|when, then| {
+            when.path("/v1/kv/data/id-contact/core")
+                .method(httpmock::Method::GET)
+                .header("X-Vault-Token", "vault_token");
+            then.status(200).json_body(json!({"data": {"data": {
+                "internal_secret": "sample_secret_1234567890178901237890",
+                "ui_signing_privkey": {"type": "RSA", "key": "..."},
+                "unrelated": "value",
+            }}}));
+        });
+        server.mock(|when, then| {
+            when.path("/v1/kv/data/id-contact/requestors")
+                .method(httpmock::Method::GET)
+                .header("X-Vault-Token", "vault_token");
+            then.status(200).json_body(json!({"data": {"data": {
+                "test": {"type": "RSA", "key": "..."},
+            }}}));
+        });
+
+        let vault = Figment::from(Serialized::defaults(json!({
+            "address": server.base_url(),
+            "mount": "kv",
+            "secret_path": "id-contact/core",
+            "requestor_keys_path": "id-contact/requestors",
+            "role_id": "core",
+            "secret_id": "very_secret",
+        })))
+        .extract::<VaultConfig>()
+        .unwrap();
+
+        let secrets = tokio_test::block_on(vault.secrets()).unwrap();
+        assert_eq!(
+            secrets["internal_secret"],
+            json!("sample_secret_1234567890178901237890")
+        );
+        assert_eq!(
+            secrets["authonly_request_keys"],
+            json!({"test": {"type": "RSA", "key": "..."}})
+        );
+        assert!(!secrets.contains_key("unrelated"));
+    }
+
+    #[test]
+    fn test_transit_sign() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/v1/transit/sign/core-ui/sha2-256")
+                .method(httpmock::Method::POST)
+                .header("X-Vault-Token", "vault_token")
+                .json_body(json!({
+                    "input": "aGVhZGVyLnBheWxvYWQ=",
+                    "marshaling_algorithm": "jws",
+                    "signature_algorithm": "pkcs1v15",
+                }));
+            then.status(200)
+                .json_body(json!({"data": {"signature": "vault:v1:c2lnbmF0dXJl"}}));
+        });
+
+        let vault = Figment::from(Serialized::defaults(json!({
+            "address": server.base_url(),
+            "token": "vault_token",
+        })))
+        .extract::<VaultConfig>()
+        .unwrap();
+        let signature = tokio_test::block_on(vault.transit_sign(
+            "transit",
+            "core-ui",
+            b"header.payload",
+            SignatureAlgorithm::RS256,
+        ))
+        .unwrap();
+        assert_eq!(signature, b"signature");
+    }
+
+    #[test]
+    fn test_secrets_unavailable() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.path("/v1/secret/data/id-contact/core");
+            then.status(403);
+        });
+
+        let vault = Figment::from(Serialized::defaults(json!({
+            "address": server.base_url(),
+            "secret_path": "id-contact/core",
+            "token": "vault_token",
+        })))
+        .extract::<VaultConfig>()
+        .unwrap();
+        assert!(tokio_test::block_on(vault.secrets()).is_err());
+    }
+
+    #[test]
+    fn test_reload_requestor_keys() {
+        let server = MockServer::start();
+        let key = json!({"type": "RSA", "key": REQUESTOR_KEY});
+        let mut keys_mock = server.mock(|when, then| {
+            when.path("/v1/secret/data/id-contact/requestors");
+            then.status(200).json_body(json!({"data": {"data": {
+                "test": {"type": "RSA", "key": "invalid"},
+                "new": key,
+            }}}));
+        });
+        let vault = Figment::from(Serialized::defaults(json!({
+            "address": server.base_url(),
+            "requestor_keys_path": "id-contact/requestors",
+            "token": "vault_token",
+        })))
+        .extract::<VaultConfig>()
+        .unwrap();
+
+        let parse = || {
+            Box::<dyn JwsVerifier>::try_from(
+                serde_json::from_value::<SignKeyConfig>(key.clone()).unwrap(),
+            )
+            .unwrap()
+        };
+        let keys: RequestorKeys = Default::default();
+        keys.write().unwrap().insert("test".into(), parse());
+        keys.write().unwrap().insert("gone".into(), parse());
+        let mut previous: HashSet<String> =
+            vec!["test".into(), "gone".into()].into_iter().collect();
+        let health = Health::default();
+
+        // The invalid key of one requestor leaves its previous key in place
+        tokio_test::block_on(vault.reload_requestor_keys(
+            "id-contact/requestors",
+            &keys,
+            &mut previous,
+            None,
+            &health,
+        ));
+        let mut loaded: Vec<String> = keys.read().unwrap().keys().cloned().collect();
+        loaded.sort();
+        assert_eq!(loaded, vec!["new", "test"]);
+        assert!(health.degraded()["requestor_keys"].contains("test"));
+
+        keys_mock.delete();
+        keys_mock = server.mock(|when, then| {
+            when.path("/v1/secret/data/id-contact/requestors");
+            then.status(200)
+                .json_body(json!({"data": {"data": {"test": key, "new": key}}}));
+        });
+        tokio_test::block_on(vault.reload_requestor_keys(
+            "id-contact/requestors",
+            &keys,
+            &mut previous,
+            None,
+            &health,
+        ));
+        keys_mock.assert();
+        assert!(health.degraded().is_empty());
+    }
+
+    #[test]
+    fn test_reload_managed_requestor_keys() {
+        let server = MockServer::start();
+        let key = json!({"type": "RSA", "key": REQUESTOR_KEY});
+        let keys_mock = server.mock(|when, then| {
+            when.path("/v1/secret/data/id-contact/requestors");
+            then.status(200)
+                .json_body(json!({"data": {"data": {"managed": key, "test": key}}}));
+        });
+        let vault = Figment::from(Serialized::defaults(json!({
+            "address": server.base_url(),
+            "requestor_keys_path": "id-contact/requestors",
+            "token": "vault_token",
+        })))
+        .extract::<VaultConfig>()
+        .unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "core-test-vault-managed-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            serde_json::to_vec(&json!({ "managed": { "key": key, "created": 0 } })).unwrap(),
+        )
+        .unwrap();
+        let keys: RequestorKeys = Default::default();
+        let managed = ManagedRequestors::load(
+            serde_json::from_value(json!({ "path": path })).unwrap(),
+            keys.clone(),
+            HashSet::new(),
+        )
+        .unwrap();
+        // Left over from a clash that existed before the requestor was managed
+        let mut previous: HashSet<String> = vec!["managed".into()].into_iter().collect();
+        let health = Health::default();
+
+        // Vault neither replaces nor removes the key of a managed requestor
+        tokio_test::block_on(vault.reload_requestor_keys(
+            "id-contact/requestors",
+            &keys,
+            &mut previous,
+            Some(&managed),
+            &health,
+        ));
+        keys_mock.assert();
+        let mut loaded: Vec<String> = keys.read().unwrap().keys().cloned().collect();
+        loaded.sort();
+        assert_eq!(loaded, vec!["managed", "test"]);
+        assert_eq!(previous, vec!["test".into()].into_iter().collect());
+        assert_eq!(
+            health.degraded()["requestor_keys"],
+            "Keys in vault ignored for managed requestors managed"
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+}